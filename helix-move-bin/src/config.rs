@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    ValueEnum,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeleteMode {
+    /// Move deleted entries to the freedesktop trash
+    #[default]
+    Trash,
+    /// Remove deleted entries for good
+    Permanent,
+}
+
+// $XDG_CONFIG_HOME/helix-move/config.toml
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub delete: DeleteMode,
+    pub rewrite_links: bool,
+    pub ids: bool,
+    /// Directory with one template per extension, e.g. `rs` seeds new
+    /// `*.rs` files
+    pub templates: Option<PathBuf>,
+    /// Theme scopes for the kinds of lines in the list, e.g.
+    /// `moved = "diff.delta.moved"`, see `token_scopes`
+    pub token_scopes: TokenScopes,
+}
+
+// One theme scope per semantic token type of the language server
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TokenScopes {
+    pub unchanged: Option<String>,
+    pub renamed: Option<String>,
+    pub moved: Option<String>,
+    pub carried: Option<String>,
+    pub deleted: Option<String>,
+    pub created: Option<String>,
+    pub changed: Option<String>,
+}
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+
+        Some(base.join("helix-move/config.toml"))
+    }
+
+    pub fn load() -> anyhow::Result<Config> {
+        let Some(path) = Self::path().filter(|p| p.is_file()) else {
+            return Ok(Config::default());
+        };
+
+        toml::from_str(&fs::read_to_string(&path)?).map_err(|e| {
+            anyhow::anyhow!("Invalid config {}: {e}", path.display())
+        })
+    }
+
+    // Theme scope of every kind of line the language server highlights
+    pub fn token_scopes(&self) -> BTreeMap<String, String> {
+        let scopes = &self.token_scopes;
+        [
+            ("unchanged", &scopes.unchanged, "ui.text"),
+            ("renamed", &scopes.renamed, "diff.delta"),
+            ("moved", &scopes.moved, "diff.delta.moved"),
+            ("carried", &scopes.carried, "ui.text.inactive"),
+            ("deleted", &scopes.deleted, "diff.minus"),
+            ("created", &scopes.created, "diff.plus"),
+            ("changed", &scopes.changed, "markup.bold"),
+        ]
+        .into_iter()
+        .map(|(class, scope, default)| {
+            let scope = scope.as_deref().unwrap_or(default);
+            (class.to_string(), scope.to_string())
+        })
+        .collect()
+    }
+
+    pub fn template_for(&self, path: &str) -> Option<PathBuf> {
+        let dir = self.templates.as_ref()?;
+        let dir = match (dir.strip_prefix("~"), std::env::var_os("HOME")) {
+            (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+            _ => dir.clone(),
+        };
+
+        let extension = Path::new(path).extension()?;
+        Some(dir.join(extension)).filter(|t| t.is_file())
+    }
+}
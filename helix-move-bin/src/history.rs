@@ -0,0 +1,304 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use helix_move_lib::{MetadataLoss, Operation, Progress, remove_entry};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeleteMode;
+use crate::roots::collect_roots;
+use crate::state::{now, state_dir};
+use crate::transaction::Header;
+use crate::trash;
+
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub root: PathBuf,
+    /// Roots of a session over several directories, relative to `root`
+    #[serde(default)]
+    pub roots: Vec<String>,
+    pub timestamp: u64,
+    /// Listing of the root right after the session
+    pub result: Vec<String>,
+    pub operations: Vec<Operation>,
+    pub deleted: Vec<Deleted>,
+    pub undone_by: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Deleted {
+    pub step: usize,
+    pub path: String,
+    /// None when deleted permanently
+    pub stored: Option<PathBuf>,
+}
+
+// Trashes or removes the staged deletions of a committed session
+pub fn store_deleted(
+    header: &Header,
+    staged: impl Fn(usize) -> PathBuf,
+    losses: &mut Vec<MetadataLoss>,
+    progress: &mut dyn FnMut(Progress),
+) -> anyhow::Result<Vec<Deleted>> {
+    let mut deleted = Vec::new();
+
+    for (step, operation) in header.operations.iter().enumerate() {
+        let Operation::Remove { path } = operation else {
+            continue;
+        };
+
+        let staged = staged(step);
+
+        // gone already when committing was interrupted before, the entry
+        // is then in the trash but no longer known to the session
+        let stored = match header.delete {
+            _ if fs::symlink_metadata(&staged).is_err() => None,
+            DeleteMode::Trash => {
+                let original = header
+                    .root
+                    .join(path.trim_end_matches('/'));
+                Some(trash::trash(&staged, &original, losses, progress)?)
+            }
+            DeleteMode::Permanent => {
+                remove_entry(&staged)?;
+                None
+            }
+        };
+
+        deleted.push(Deleted {
+            step,
+            path: path.clone(),
+            stored,
+        });
+    }
+
+    Ok(deleted)
+}
+
+impl Session {
+    fn dir() -> anyhow::Result<PathBuf> {
+        Ok(state_dir()?.join("history"))
+    }
+
+    pub fn record(
+        header: &Header,
+        deleted: Vec<Deleted>,
+    ) -> anyhow::Result<Session> {
+        let session = Session {
+            id: header.id.clone(),
+            root: header.root.clone(),
+            roots: header.roots.clone(),
+            timestamp: now(),
+            result: collect_roots(&header.root, &header.roots)?,
+            operations: header.operations.clone(),
+            deleted,
+            undone_by: None,
+        };
+
+        session.save()?;
+        Ok(session)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = Self::dir()?
+            .join(&self.id)
+            .join("session.json");
+        let temp_path = path.with_extension("tmp");
+
+        fs::create_dir_all(Self::dir()?.join(&self.id))?;
+
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Session> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    // Oldest first
+    pub fn list() -> anyhow::Result<Vec<Session>> {
+        let dir = Self::dir()?;
+
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut sessions = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path().join("session.json");
+
+            if path.is_file() {
+                sessions.push(Self::load(&path)?);
+            }
+        }
+
+        sessions
+            .sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(sessions)
+    }
+
+    pub fn inverse(&self) -> anyhow::Result<Vec<Operation>> {
+        let mut operations = Vec::new();
+
+        for (step, operation) in self.operations.iter().enumerate().rev() {
+            operations.push(match operation {
+                Operation::CreateDir { path }
+                | Operation::CreateFile { path, .. }
+                | Operation::Copy { to: path, .. } => {
+                    Operation::Remove { path: path.clone() }
+                }
+                // a restore of an earlier undo, deleting it again
+                Operation::Restore { to, .. } => {
+                    Operation::Remove { path: to.clone() }
+                }
+                Operation::Rename { from, to } => Operation::Rename {
+                    from: to.clone(),
+                    to: from.clone(),
+                },
+                Operation::Relink { path, from, to } => Operation::Relink {
+                    path: path.clone(),
+                    from: to.clone(),
+                    to: from.clone(),
+                },
+                Operation::Remove { path } => {
+                    let Some(stored) = self
+                        .deleted
+                        .iter()
+                        .find(|d| d.step == step)
+                        .and_then(|d| d.stored.as_ref())
+                    else {
+                        anyhow::bail!(
+                            "{path} was deleted permanently and cannot be \
+                             restored"
+                        );
+                    };
+
+                    Operation::Restore {
+                        trash_path: stored.clone(),
+                        to: path.clone(),
+                    }
+                }
+            });
+        }
+
+        Ok(operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::isolated;
+    use crate::transaction::apply;
+
+    #[test]
+    fn trashed_entries_are_restored_by_the_inverse() {
+        let (_guard, state) = isolated();
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("d")).unwrap();
+        fs::write(root.path().join("d/f"), "f").unwrap();
+        fs::write(root.path().join("a"), "a").unwrap();
+
+        let operations = [
+            Operation::Remove {
+                path: "a".to_string(),
+            },
+            Operation::Remove {
+                path: "d/".to_string(),
+            },
+        ];
+        let applied =
+            apply(root.path(), &[], &operations, DeleteMode::Trash).unwrap();
+        assert!(
+            fs::read_dir(root.path())
+                .unwrap()
+                .next()
+                .is_none()
+        );
+
+        let session = Session::list()
+            .unwrap()
+            .into_iter()
+            .find(|s| s.id == applied.id)
+            .unwrap();
+        let trash = state.path().join("data/Trash");
+        let stored: Vec<&PathBuf> = session
+            .deleted
+            .iter()
+            .filter_map(|d| d.stored.as_ref())
+            .collect();
+        assert_eq!(stored, [&trash.join("files/a"), &trash.join("files/d")]);
+        assert!(trash.join("info/d.trashinfo").is_file());
+
+        let inverse = session.inverse().unwrap();
+        assert_eq!(
+            inverse,
+            [
+                Operation::Restore {
+                    trash_path: trash.join("files/d"),
+                    to: "d/".to_string(),
+                },
+                Operation::Restore {
+                    trash_path: trash.join("files/a"),
+                    to: "a".to_string(),
+                },
+            ]
+        );
+
+        let undone =
+            apply(root.path(), &[], &inverse, DeleteMode::Trash).unwrap();
+        for stored in stored {
+            trash::forget(stored).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(root.path().join("d/f")).unwrap(), "f");
+        assert_eq!(fs::read_to_string(root.path().join("a")).unwrap(), "a");
+        assert!(
+            fs::read_dir(trash.join("info"))
+                .unwrap()
+                .next()
+                .is_none()
+        );
+
+        // undoing the undo deletes them again
+        let undo = Session::list()
+            .unwrap()
+            .into_iter()
+            .find(|s| s.id == undone.id)
+            .unwrap();
+        assert_eq!(
+            undo.inverse().unwrap(),
+            [
+                Operation::Remove {
+                    path: "a".to_string(),
+                },
+                Operation::Remove {
+                    path: "d/".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn permanent_deletions_cannot_be_undone() {
+        let (_guard, _state) = isolated();
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a"), "a").unwrap();
+
+        let operations = [Operation::Remove {
+            path: "a".to_string(),
+        }];
+        apply(root.path(), &[], &operations, DeleteMode::Permanent).unwrap();
+
+        let session = Session::list().unwrap().remove(0);
+        assert!(session.inverse().is_err());
+    }
+}
@@ -1,21 +1,46 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use config::{Config, DeleteMode};
 use helix_move_lib::{
-    add_missing_directories, build_rules, create_edit_rules, filter_full_rules,
-    normalize_rules, EditRule,
+    DiagnosticKind, EntryKind, Error, FullRule, LineRules, Operation, Rule,
+    add_missing_directories, affected_entries, build_line_rules,
+    build_rules_by_id, canonicalize_rules, filter_full_rules, format_timestamp,
+    format_with_ids, has_errors, normalize_rules, plan_operations,
+    plan_relinks, rescued_entries, simulate_operations, validate_lines,
+    validate_roots,
 };
+use history::Session;
+use roots::{Roots, collect_roots, describe_roots};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitCode};
 use std::str::FromStr;
 use tempfile::{Builder, TempDir};
+use transaction::{Applied, Journal};
+
+mod config;
+mod history;
+mod roots;
+mod state;
+mod transaction;
+mod trash;
+
+/// Exit code of a dry run that found changes to apply
+const DRY_RUN_CHANGES: u8 = 2;
 
 #[derive(Parser)]
+#[command(
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Directory whose files should be listed
-    dir: PathBuf,
+    /// Directories whose files should be listed, several are listed with
+    /// their path as prefix and entries can move between them
+    #[arg(required = true)]
+    dirs: Vec<PathBuf>,
 
     /// Path to the LSP binary (defaults to workspace build)
     #[arg(long)]
@@ -25,9 +50,60 @@ struct Args {
     #[arg(long)]
     helix_bin: Option<PathBuf>,
 
-    /// Show what would happen without moving files
+    /// Show what would happen without moving files (exits with 2 if there
+    /// are changes)
     #[arg(long)]
     dry_run: bool,
+
+    /// What happens to deleted entries (defaults to the `delete` setting
+    /// of the config, or trash)
+    #[arg(long, value_enum)]
+    delete: Option<DeleteMode>,
+
+    /// Rewrite relative symlink targets so they still resolve after moving
+    #[arg(long)]
+    rewrite_links: bool,
+
+    /// Prefix every line with an id, so lines can be reordered, removed
+    /// (deleting the entry) or added (creating one)
+    #[arg(long)]
+    ids: bool,
+
+    /// Allow destinations outside of the directory, like `../sibling/`
+    #[arg(long)]
+    allow_outside_root: bool,
+
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Finish or revert a session that was interrupted by a crash
+    Recover {
+        /// Session to recover (needed when several were interrupted)
+        session: Option<String>,
+
+        /// Run the remaining steps of the session
+        #[arg(long, conflicts_with = "revert")]
+        finish: bool,
+
+        /// Undo the steps the session already completed
+        #[arg(long)]
+        revert: bool,
+    },
+
+    /// Revert the most recent (or the given) applied session
+    Undo {
+        /// Session to undo (see `helix-move history`)
+        session: Option<String>,
+    },
+
+    /// List applied sessions, or show the operations of one
+    History {
+        /// Session to show
+        session: Option<String>,
+    },
 }
 
 #[derive(Serialize)]
@@ -64,7 +140,15 @@ struct LanguageServer {
 #[derive(Serialize)]
 struct InitOptions {
     file_list_file: String,
+    root: PathBuf,
+    roots: Vec<String>,
+    allow_outside_root: bool,
     files: Vec<String>,
+    links: BTreeMap<String, String>,
+    kinds: BTreeMap<String, String>,
+    hard_links: BTreeMap<String, Vec<String>>,
+    ids: bool,
+    token_scopes: BTreeMap<String, String>,
 }
 
 #[derive(Serialize)]
@@ -128,6 +212,78 @@ pub fn collect_paths(root: impl AsRef<Path>) -> anyhow::Result<Vec<String>> {
     Ok(result)
 }
 
+// Targets of the listed symlinks
+fn collect_links(
+    root: &Path,
+    entries: &[String],
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut links = BTreeMap::new();
+
+    for entry in entries {
+        let path = root.join(entry);
+
+        if fs::symlink_metadata(&path)?.is_symlink() {
+            let target = fs::read_link(&path)?;
+            links.insert(entry.clone(), target.to_string_lossy().to_string());
+        }
+    }
+
+    Ok(links)
+}
+
+// Kinds of the listed fifos, sockets and device nodes
+fn collect_kinds(
+    root: &Path,
+    entries: &[String],
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut kinds = BTreeMap::new();
+
+    for entry in entries {
+        let kind =
+            EntryKind::of(fs::symlink_metadata(root.join(entry))?.file_type());
+
+        if kind.is_special() {
+            kinds.insert(entry.clone(), kind.to_string());
+        }
+    }
+
+    Ok(kinds)
+}
+
+// Listed entries that share their inode, with the other listed links to it
+fn collect_hard_links(
+    root: &Path,
+    entries: &[String],
+) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let mut inodes: BTreeMap<(u64, u64), Vec<&String>> = BTreeMap::new();
+
+    for entry in entries {
+        let metadata = fs::symlink_metadata(root.join(entry))?;
+
+        if !metadata.is_dir() && metadata.nlink() > 1 {
+            inodes
+                .entry((metadata.dev(), metadata.ino()))
+                .or_default()
+                .push(entry);
+        }
+    }
+
+    let mut hard_links = BTreeMap::new();
+
+    for group in inodes.into_values() {
+        for &entry in &group {
+            let others = group
+                .iter()
+                .filter(|&&other| other != entry)
+                .map(|&other| other.clone())
+                .collect();
+            hard_links.insert(entry.clone(), others);
+        }
+    }
+
+    Ok(hard_links)
+}
+
 fn resolve_lsp_path(args: &Args) -> anyhow::Result<PathBuf> {
     if let Some(path) = &args.lsp {
         return Ok(path.clone());
@@ -172,11 +328,34 @@ fn confirm() -> anyhow::Result<bool> {
 // ============================
 //
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     let args = Args::parse();
+    let user_config = Config::load()?;
+
+    match &args.action {
+        Some(Action::Recover {
+            session,
+            finish,
+            revert,
+        }) => return recover(session.as_deref(), *finish, *revert),
+        Some(Action::Undo { session }) => {
+            return undo(session.as_deref(), user_config.delete);
+        }
+        Some(Action::History { session }) => {
+            return history(session.as_deref());
+        }
+        None => {}
+    }
 
-    if !args.dir.is_dir() {
-        anyhow::bail!("Provided path is not a directory");
+    let roots = Roots::new(&args.dirs)?;
+    let dir = roots.base.as_path();
+
+    if let Some(journal) = transaction::pending_for(dir)? {
+        anyhow::bail!(
+            "Session {} in this directory was interrupted, run \
+             `helix-move recover --finish` or `--revert` first",
+            journal.header.id
+        );
     }
 
     let lsp_path = resolve_lsp_path(&args)?;
@@ -191,10 +370,19 @@ fn main() -> anyhow::Result<()> {
     fs::create_dir_all(&helix_path)?;
 
     // ---- Collect original entries (FILES + DIRECTORIES) ----
-    let original_entries: Vec<String> = collect_paths(&args.dir)?;
+    let original_entries: Vec<String> = roots.collect()?;
+    let links = collect_links(dir, &original_entries)?;
+    let kinds = collect_kinds(dir, &original_entries)?;
+    let hard_links = collect_hard_links(dir, &original_entries)?;
+
+    let ids = args.ids || user_config.ids;
 
     let list_file = base_path.join("file-list");
-    fs::write(&list_file, original_entries.join("\n"))?;
+    let listing = match ids {
+        true => format_with_ids(&original_entries),
+        false => original_entries.clone(),
+    };
+    fs::write(&list_file, listing.join("\n"))?;
 
     // ---- Generate languages.toml ----
     let mut language_servers = HashMap::new();
@@ -208,7 +396,15 @@ fn main() -> anyhow::Result<()> {
             args: vec![],
             config: InitOptions {
                 file_list_file: list_file.display().to_string(),
+                root: dir.to_path_buf(),
+                roots: roots.prefixes.clone(),
+                allow_outside_root: args.allow_outside_root,
                 files: original_entries.clone(),
+                links: links.clone(),
+                kinds,
+                hard_links,
+                ids,
+                token_scopes: user_config.token_scopes(),
             },
         },
     );
@@ -259,86 +455,396 @@ fn main() -> anyhow::Result<()> {
         .collect();
 
     // Phase 1
-    let rules = build_rules(&original_entries, &edited_entries);
+    let LineRules { rules, lines } =
+        match build(ids, &original_entries, &edited_entries) {
+            Ok(built) => built,
+            Err(error) => return abort(error),
+        };
+
+    let rules = canonicalize_rules(dir, &rules);
 
     // Phase 2
     let normalized = normalize_rules(&rules);
 
-    // Phase 3
-    let full_rules = add_missing_directories(&normalized);
+    let mut diagnostics = validate_lines(&normalized, &lines);
+    if !roots.prefixes.is_empty() {
+        let outside = validate_roots(&normalized, &lines, &roots.prefixes);
+        diagnostics.extend(outside);
+        diagnostics.sort_by_key(|d| d.line);
+    }
+    if args.allow_outside_root {
+        diagnostics.retain(|d| d.kind != DiagnosticKind::OutsideRoot);
+    }
+
+    for diagnostic in &diagnostics {
+        eprintln!("{diagnostic}");
+    }
+
+    if has_errors(&diagnostics) {
+        eprintln!("No files were changed");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let rewrite_links = args.rewrite_links || user_config.rewrite_links;
+    let links = if rewrite_links {
+        links
+    } else {
+        BTreeMap::new()
+    };
+
+    let (full_rules, mut operations) = match plan(&normalized, &links) {
+        Ok(plan) => plan,
+        Err(error) => return abort(error),
+    };
+
+    for operation in &mut operations {
+        if let Operation::CreateFile { path, template } = operation {
+            *template = user_config.template_for(path);
+        }
+    }
 
     let filtered_rules = filter_full_rules(&full_rules);
 
     if filtered_rules.is_empty() {
         println!("No Changes");
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // entries carried along by a directory are indented below it
+    let mut cascades: HashMap<&str, usize> = HashMap::new();
+    for rule in &normalized {
+        if let Rule::Deleted { path } | Rule::Renamed { from: path, .. } = rule
+        {
+            for affected in affected_entries(&normalized, path) {
+                if let Rule::Deleted { path }
+                | Rule::Renamed { from: path, .. } = affected
+                {
+                    *cascades
+                        .entry(path.as_str())
+                        .or_default() += 1;
+                }
+            }
+        }
     }
 
     println!("Changes:");
     for rule in filtered_rules {
-        println!("{}", rule);
+        let level = match &rule {
+            FullRule::Deleted { path }
+            | FullRule::Renamed { from: path, .. } => cascades
+                .get(path.as_str())
+                .copied()
+                .unwrap_or(0),
+            _ => 0,
+        };
+        println!("{}{}", "  ".repeat(level), rule);
+    }
+    for operation in &operations {
+        if let Operation::Relink { path, to, .. } = operation {
+            println!("@ {path} -> {to}");
+        }
+    }
+
+    let rescued = rescued_entries(&full_rules);
+    if !rescued.is_empty() {
+        println!("\nKept out of deleted directories:");
+        for rule in rescued {
+            println!("{}", rule);
+        }
+    }
+
+    let delete = args
+        .delete
+        .unwrap_or(user_config.delete);
+
+    if operations
+        .iter()
+        .any(|op| matches!(op, Operation::Remove { .. }))
+    {
+        match delete {
+            DeleteMode::Trash => {
+                println!("\nDeleted entries are moved to the trash")
+            }
+            DeleteMode::Permanent => {
+                println!("\nDeleted entries are removed PERMANENTLY")
+            }
+        }
+    }
+
+    if args.dry_run {
+        if let Err(error) = print_dry_run(&original_entries, &operations) {
+            return abort(error);
+        }
+
+        return Ok(ExitCode::from(DRY_RUN_CHANGES));
     }
 
     if !confirm()? {
         println!("Cancelled");
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
     }
 
-    // Phase 4
-    let edit_rules = create_edit_rules(&full_rules);
-
-    build_and_replace(&args.dir, &edit_rules)?;
+    let applied =
+        transaction::apply(dir, &roots.prefixes, &operations, delete)?;
 
     println!("Applied successfully");
-    Ok(())
+    report(&applied);
+    Ok(ExitCode::SUCCESS)
 }
 
-fn build_and_replace(
-    root: &Path,
-    edit_rules: &[EditRule],
-) -> anyhow::Result<()> {
-    let root = root.canonicalize()?;
+// Phase 1, positional or by line id
+fn build(
+    ids: bool,
+    original: &[String],
+    edited: &[String],
+) -> Result<LineRules, Error> {
+    if ids {
+        build_rules_by_id(original, edited)
+    } else {
+        build_line_rules(original, edited)
+    }
+}
 
-    let parent = root
-        .parent()
-        .ok_or(anyhow::anyhow!("Invalid root"))?;
+fn plan(
+    normalized: &[Rule],
+    links: &BTreeMap<String, String>,
+) -> Result<(Vec<FullRule>, Vec<Operation>), Error> {
+    // Phase 3
+    let full_rules = add_missing_directories(normalized)?;
 
-    let temp_dir: TempDir = Builder::new()
-        .prefix(".helix-move-builder.")
-        .tempdir_in(parent)?;
+    // Phase 5
+    let mut operations = plan_operations(&full_rules)?;
 
-    for rule in edit_rules {
-        rule.apply(&root, temp_dir.path())?;
-    }
+    // Phase 6
+    operations.extend(plan_relinks(&full_rules, links));
 
-    let temp_dir_path = temp_dir.keep();
+    Ok((full_rules, operations))
+}
+
+fn print_dry_run(
+    original: &[String],
+    operations: &[Operation],
+) -> Result<(), Error> {
+    let simulated = simulate_operations(original, operations)?;
 
-    replace_contents(&temp_dir_path, &root)?;
+    println!("\nOperations:");
+    for (i, operation) in operations.iter().enumerate() {
+        println!("{:>4}. {}", i + 1, operation);
+    }
 
+    println!("\nResult:");
+    for entry in simulated {
+        println!("{entry}");
+    }
+
+    println!("\nDry run, no files were changed");
     Ok(())
 }
 
-fn replace_contents(src: &Path, dst: &Path) -> anyhow::Result<()> {
-    for entry in fs::read_dir(dst)? {
-        let entry = entry?;
-        let path = entry.path();
+// Moves that took the slow path across filesystems, and metadata the
+// copies could not preserve
+fn report(applied: &Applied) {
+    if !applied.slow_moves.is_empty() {
+        eprintln!(
+            "\nCopied across filesystems (verified, then the originals \
+             were removed):"
+        );
+        for slow_move in &applied.slow_moves {
+            eprintln!("  {slow_move}");
+        }
+    }
+
+    if applied.losses.is_empty() {
+        return;
+    }
+
+    eprintln!("\nCould not preserve all metadata:");
+    for loss in &applied.losses {
+        eprintln!("  {loss}");
+    }
+}
+
+fn abort(error: Error) -> anyhow::Result<ExitCode> {
+    eprintln!("Error: {error}");
+    eprintln!("No files were changed");
+    Ok(ExitCode::FAILURE)
+}
+
+fn recover(
+    session: Option<&str>,
+    finish: bool,
+    revert: bool,
+) -> anyhow::Result<ExitCode> {
+    let journals = Journal::list()?;
 
-        if path.is_dir() {
-            fs::remove_dir_all(&path)?;
+    if journals.is_empty() {
+        println!("No interrupted sessions");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let selected: Vec<&Journal> = journals
+        .iter()
+        .filter(|j| session.is_none_or(|id| j.header.id == id))
+        .collect();
+
+    if !(finish || revert) || selected.len() != 1 {
+        println!("Interrupted sessions:");
+        for journal in &selected {
+            println!(
+                "{}  {}  {}/{} steps{}",
+                journal.header.id,
+                describe_roots(&journal.header.root, &journal.header.roots),
+                journal.done,
+                journal.header.operations.len(),
+                if journal.committed { ", committed" } else { "" }
+            );
+        }
+
+        if selected.len() > 1 {
+            println!("\nPick one session to recover");
+        } else if selected.is_empty() {
+            anyhow::bail!("No interrupted session matches");
         } else {
-            fs::remove_file(&path)?;
+            println!("\nUse --finish or --revert to recover it");
         }
+
+        return Ok(ExitCode::FAILURE);
     }
 
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let from = entry.path();
-        let to = dst.join(entry.file_name());
+    let id = selected[0].header.id.clone();
+    let journal = journals
+        .into_iter()
+        .find(|j| j.header.id == id)
+        .expect("selected journal is listed");
 
-        fs::rename(from, to)?;
+    if finish {
+        let applied = journal.finish()?;
+        println!("Session {id} finished");
+        report(&applied);
+    } else {
+        journal.revert()?;
+        println!("Session {id} reverted");
     }
 
-    fs::remove_dir(src)?;
+    Ok(ExitCode::SUCCESS)
+}
 
-    Ok(())
+fn undo(session: Option<&str>, delete: DeleteMode) -> anyhow::Result<ExitCode> {
+    let sessions = Session::list()?;
+
+    let found = match session {
+        Some(id) => sessions
+            .into_iter()
+            .find(|s| s.id == id),
+        None => sessions
+            .into_iter()
+            .rev()
+            .find(|s| s.undone_by.is_none()),
+    };
+
+    let Some(mut session) = found else {
+        anyhow::bail!("No session to undo");
+    };
+
+    if let Some(undone_by) = &session.undone_by {
+        anyhow::bail!(
+            "Session {} was already undone by session {undone_by}",
+            session.id
+        );
+    }
+
+    if let Some(journal) = transaction::pending_for(&session.root)? {
+        anyhow::bail!(
+            "Session {} in this directory was interrupted, run \
+             `helix-move recover` first",
+            journal.header.id
+        );
+    }
+
+    let current = collect_roots(&session.root, &session.roots)?;
+
+    if current != session.result {
+        anyhow::bail!(
+            "{} has changed since session {}, refusing to undo it",
+            describe_roots(&session.root, &session.roots),
+            session.id
+        );
+    }
+
+    let operations = session.inverse()?;
+
+    println!(
+        "Undo session {} in {}:",
+        session.id,
+        describe_roots(&session.root, &session.roots)
+    );
+    for operation in &operations {
+        println!("{operation}");
+    }
+
+    if !confirm()? {
+        println!("Cancelled");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let applied =
+        transaction::apply(&session.root, &session.roots, &operations, delete)?;
+
+    for stored in session
+        .deleted
+        .iter()
+        .filter_map(|d| d.stored.as_ref())
+    {
+        trash::forget(stored)?;
+    }
+
+    session.undone_by = Some(applied.id.clone());
+    session.save()?;
+
+    println!("Undone successfully");
+    report(&applied);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn history(session: Option<&str>) -> anyhow::Result<ExitCode> {
+    let sessions = Session::list()?;
+
+    if let Some(id) = session {
+        let Some(session) = sessions.iter().find(|s| s.id == id) else {
+            anyhow::bail!("No session {id}");
+        };
+
+        println!(
+            "Session {} in {}",
+            session.id,
+            describe_roots(&session.root, &session.roots)
+        );
+        for operation in &session.operations {
+            println!("{operation}");
+        }
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if sessions.is_empty() {
+        println!("No sessions");
+    }
+
+    for session in &sessions {
+        let undone = match &session.undone_by {
+            Some(id) => format!(", undone by {id}"),
+            None => String::new(),
+        };
+
+        println!(
+            "{}  {}  {}  {} operations{}",
+            session.id,
+            format_timestamp(session.timestamp),
+            describe_roots(&session.root, &session.roots),
+            session.operations.len(),
+            undone
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
 }
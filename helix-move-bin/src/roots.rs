@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+
+use crate::collect_paths;
+
+//
+// ============================
+// Roots
+// ============================
+//
+// A session over several directories works in their closest common
+// ancestor. Every root is listed as a "{prefix}/" line relative to it, with
+// its entries below, so renames can cross from one root to another.
+//
+
+pub struct Roots {
+    pub base: PathBuf,
+    /// Empty for a single root, which is the base itself
+    pub prefixes: Vec<String>,
+}
+
+impl Roots {
+    pub fn new(dirs: &[PathBuf]) -> anyhow::Result<Roots> {
+        let mut roots = Vec::new();
+
+        for dir in dirs {
+            if !dir.is_dir() {
+                anyhow::bail!("{} is not a directory", dir.display());
+            }
+            roots.push(dir.canonicalize()?);
+        }
+
+        if let [root] = &roots[..] {
+            return Ok(Roots {
+                base: root.clone(),
+                prefixes: Vec::new(),
+            });
+        }
+
+        let mut base = roots[0].clone();
+        while !roots
+            .iter()
+            .all(|root| root.starts_with(&base))
+        {
+            base.pop();
+        }
+
+        let mut prefixes = Vec::new();
+
+        for (i, root) in roots.iter().enumerate() {
+            if roots[..i].contains(root) {
+                anyhow::bail!("{} is given more than once", root.display());
+            }
+
+            let relative = root.strip_prefix(&base)?;
+
+            if relative.as_os_str().is_empty()
+                || roots
+                    .iter()
+                    .any(|other| other != root && root.starts_with(other))
+            {
+                anyhow::bail!(
+                    "{} is inside another of the given directories",
+                    root.display()
+                );
+            }
+
+            prefixes.push(format!("{}/", relative.to_string_lossy()));
+        }
+
+        prefixes.sort();
+        Ok(Roots { base, prefixes })
+    }
+
+    pub fn collect(&self) -> anyhow::Result<Vec<String>> {
+        collect_roots(&self.base, &self.prefixes)
+    }
+}
+
+// Entries of every root, relative to `base`
+pub fn collect_roots(
+    base: &Path,
+    prefixes: &[String],
+) -> anyhow::Result<Vec<String>> {
+    if prefixes.is_empty() {
+        return collect_paths(base);
+    }
+
+    let mut entries = Vec::new();
+
+    for prefix in prefixes {
+        entries.push(prefix.clone());
+        entries.extend(
+            collect_paths(base.join(prefix))?
+                .into_iter()
+                .map(|entry| format!("{prefix}{entry}")),
+        );
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+pub fn describe_roots(base: &Path, prefixes: &[String]) -> String {
+    if prefixes.is_empty() {
+        return base.display().to_string();
+    }
+
+    prefixes
+        .iter()
+        .map(|prefix| base.join(prefix.trim_end_matches('/')))
+        .map(|root| root.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
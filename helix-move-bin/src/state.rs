@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn state_dir() -> anyhow::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").ok_or(anyhow::anyhow!(
+                "Neither XDG_STATE_HOME nor HOME is set"
+            ))?;
+            PathBuf::from(home).join(".local/state")
+        }
+    };
+
+    Ok(base.join("helix-move"))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn session_id() -> String {
+    format!("{}-{}", now(), std::process::id())
+}
+
+// Points the state and trash directories at a fresh directory. Tests that
+// hold the guard run one at a time, nothing else reads the environment.
+#[cfg(test)]
+pub fn isolated() -> (std::sync::MutexGuard<'static, ()>, tempfile::TempDir) {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    let guard = LOCK
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let dir = tempfile::tempdir().unwrap();
+
+    unsafe {
+        std::env::set_var("XDG_STATE_HOME", dir.path().join("state"));
+        std::env::set_var("XDG_DATA_HOME", dir.path().join("data"));
+    }
+
+    (guard, dir)
+}
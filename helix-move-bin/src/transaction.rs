@@ -0,0 +1,734 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use helix_move_lib::{
+    Error, HardLinks, MetadataLoss, Operation, Progress, SlowMove, move_entry,
+    remove_entry, root_of, settle_move,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeleteMode;
+use crate::history::{Session, store_deleted};
+use crate::state::{session_id, state_dir};
+
+//
+// ============================
+// Journal
+// ============================
+//
+// One JSON line with the header, followed by one line per completed or
+// reverted step. Every line is synced before the next step runs, so after
+// a crash the journal tells how far the session got.
+//
+
+#[derive(Serialize, Deserialize)]
+pub struct Header {
+    pub id: String,
+    pub root: PathBuf,
+    /// Roots of a session over several directories, relative to `root`
+    #[serde(default)]
+    pub roots: Vec<String>,
+    pub operations: Vec<Operation>,
+    /// What happens to deleted entries once the session is committed
+    #[serde(default)]
+    pub delete: DeleteMode,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+enum Record {
+    Done { step: usize },
+    Reverted { step: usize },
+    Committed,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    pub header: Header,
+    pub done: usize,
+    pub committed: bool,
+    /// Metadata that could not be preserved by the steps run so far
+    pub losses: Vec<MetadataLoss>,
+    /// Moves of the steps run so far that had to copy across filesystems
+    pub slow_moves: Vec<SlowMove>,
+}
+
+pub struct Applied {
+    pub id: String,
+    pub losses: Vec<MetadataLoss>,
+    pub slow_moves: Vec<SlowMove>,
+}
+
+impl Journal {
+    fn dir() -> anyhow::Result<PathBuf> {
+        Ok(state_dir()?.join("journal"))
+    }
+
+    pub fn create(
+        root: &Path,
+        roots: &[String],
+        operations: &[Operation],
+        delete: DeleteMode,
+    ) -> anyhow::Result<Journal> {
+        let dir = Self::dir()?;
+        fs::create_dir_all(&dir)?;
+
+        let header = Header {
+            id: session_id(),
+            root: root.to_path_buf(),
+            roots: roots.to_vec(),
+            operations: operations.to_vec(),
+            delete,
+        };
+
+        let path = dir.join(format!("{}.jsonl", header.id));
+        let temp_path = path.with_extension("tmp");
+
+        {
+            let mut file = File::create(&temp_path)?;
+            writeln!(file, "{}", serde_json::to_string(&header)?)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&temp_path, &path)?;
+        File::open(&dir)?.sync_all()?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)?;
+
+        Ok(Journal {
+            path,
+            file,
+            header,
+            done: 0,
+            committed: false,
+            losses: Vec::new(),
+            slow_moves: Vec::new(),
+        })
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Journal> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines();
+
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => anyhow::bail!("{} is empty", path.display()),
+        };
+
+        let mut done = 0;
+        let mut committed = false;
+
+        for line in lines {
+            // a crash can leave the last line half written
+            let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                break;
+            };
+
+            match record {
+                Record::Done { step } => done = step + 1,
+                Record::Reverted { step } => done = step,
+                Record::Committed => committed = true,
+            }
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)?;
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+            header,
+            done,
+            committed,
+            losses: Vec::new(),
+            slow_moves: Vec::new(),
+        })
+    }
+
+    pub fn list() -> anyhow::Result<Vec<Journal>> {
+        let dir = Self::dir()?;
+
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut journals = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path
+                .extension()
+                .is_some_and(|e| e == "jsonl")
+            {
+                journals.push(Self::open(&path)?);
+            }
+        }
+
+        journals.sort_by(|a, b| a.header.id.cmp(&b.header.id));
+        Ok(journals)
+    }
+
+    fn record(&mut self, record: Record) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&record)?)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // Deleted entries are parked in their root until the session is
+    // committed
+    fn staging(&self, path: &str) -> String {
+        let root = root_of(path, &self.header.roots).unwrap_or_default();
+        format!("{root}.helix-move-{}", self.header.id)
+    }
+
+    fn stagings(&self) -> Vec<String> {
+        match self.header.roots.is_empty() {
+            true => vec![self.staging("")],
+            false => self
+                .header
+                .roots
+                .iter()
+                .map(|root| self.staging(root))
+                .collect(),
+        }
+    }
+
+    fn staged(&self, step: usize) -> String {
+        let path = match &self.header.operations[step] {
+            Operation::Remove { path } => path.as_str(),
+            _ => "",
+        };
+
+        format!("{}/{step}", self.staging(path))
+    }
+
+    //
+    // ---- Steps ----
+    //
+
+    fn run_step(
+        &self,
+        step: usize,
+        links: &mut HardLinks,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<Vec<MetadataLoss>, Error> {
+        let root = &self.header.root;
+
+        match &self.header.operations[step] {
+            Operation::Remove { path } => {
+                let staging = root.join(self.staging(path));
+                fs::create_dir_all(&staging).map_err(Error::io(staging))?;
+
+                Operation::Rename {
+                    from: path.clone(),
+                    to: self.staged(step),
+                }
+                .apply_with_progress(root, links, progress)
+            }
+            operation => operation.apply_with_progress(root, links, progress),
+        }
+    }
+
+    // Losses while rolling back are not reported, the entries end up where
+    // they started
+    fn revert_step(&self, step: usize) -> Result<Vec<MetadataLoss>, Error> {
+        let root = &self.header.root;
+
+        match &self.header.operations[step] {
+            Operation::CreateDir { path } => {
+                let full_path = root.join(path);
+                fs::remove_dir(&full_path).map_err(Error::io(full_path))?;
+                Ok(Vec::new())
+            }
+            Operation::CreateFile { path, .. } => {
+                let full_path = root.join(path);
+                fs::remove_file(&full_path).map_err(Error::io(full_path))?;
+                Ok(Vec::new())
+            }
+            Operation::Rename { from, to } => Operation::Rename {
+                from: to.clone(),
+                to: from.clone(),
+            }
+            .apply(root),
+            Operation::Copy { to, .. } => {
+                remove_entry(&root.join(to.trim_end_matches('/')))?;
+                Ok(Vec::new())
+            }
+            Operation::Remove { path } => Operation::Rename {
+                from: self.staged(step),
+                to: path.clone(),
+            }
+            .apply(root),
+            Operation::Restore { trash_path, to } => move_entry(
+                &root.join(to.trim_end_matches('/')),
+                trash_path,
+                &mut |_| {},
+            ),
+            Operation::Relink { path, from, to } => Operation::Relink {
+                path: path.clone(),
+                from: to.clone(),
+                to: from.clone(),
+            }
+            .apply(root),
+        }
+    }
+
+    // Source and destination of a step that moves an entry, the move may
+    // have copied across filesystems
+    fn moved_paths(&self, step: usize) -> Option<(PathBuf, PathBuf)> {
+        let root = &self.header.root;
+        let full_path = |path: &str| root.join(path.trim_end_matches('/'));
+
+        match self.header.operations.get(step)? {
+            Operation::Rename { from, to } => {
+                Some((full_path(from), full_path(to)))
+            }
+            Operation::Restore { trash_path, to } => {
+                Some((trash_path.clone(), full_path(to)))
+            }
+            Operation::Remove { path } => {
+                Some((full_path(path), full_path(&self.staged(step))))
+            }
+            _ => None,
+        }
+    }
+
+    // Probes the disk, used for the one step a crash may have interrupted
+    fn step_applied(&self, step: usize) -> bool {
+        let root = &self.header.root;
+        let exists = |path: &str| {
+            fs::symlink_metadata(root.join(path.trim_end_matches('/'))).is_ok()
+        };
+
+        match &self.header.operations[step] {
+            Operation::CreateDir { path }
+            | Operation::CreateFile { path, .. }
+            | Operation::Copy { to: path, .. } => exists(path),
+            Operation::Rename { from, to } => !exists(from) && exists(to),
+            Operation::Remove { path } => {
+                !exists(path) && exists(&self.staged(step))
+            }
+            Operation::Restore { trash_path, to } => {
+                fs::symlink_metadata(trash_path).is_err() && exists(to)
+            }
+            Operation::Relink { path, to, .. } => {
+                fs::read_link(root.join(path))
+                    .is_ok_and(|target| target == Path::new(to))
+            }
+        }
+    }
+
+    //
+    // ---- Session ----
+    //
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        // entries sharing an inode may be moved by different steps
+        let mut links = HardLinks::default();
+
+        for step in self.done..self.header.operations.len() {
+            let mut slow_moves = Vec::new();
+            let losses = self.run_step(
+                step,
+                &mut links,
+                &mut show_progress(&mut slow_moves),
+            )?;
+            self.losses.extend(losses);
+            self.slow_moves.extend(slow_moves);
+            self.record(Record::Done { step })?;
+            self.done = step + 1;
+        }
+
+        Ok(())
+    }
+
+    fn commit(mut self) -> anyhow::Result<Applied> {
+        self.record(Record::Committed)?;
+
+        let root = &self.header.root;
+        let mut losses = std::mem::take(&mut self.losses);
+        let mut slow_moves = std::mem::take(&mut self.slow_moves);
+        let deleted = store_deleted(
+            &self.header,
+            |step| root.join(self.staged(step)),
+            &mut losses,
+            &mut show_progress(&mut slow_moves),
+        )?;
+
+        for staging in self.stagings() {
+            let staging = root.join(staging);
+            if staging.exists() {
+                fs::remove_dir_all(staging)?;
+            }
+        }
+
+        Session::record(&self.header, deleted)?;
+
+        fs::remove_file(&self.path)?;
+        Ok(Applied {
+            id: self.header.id,
+            losses,
+            slow_moves,
+        })
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<Applied> {
+        if !self.committed {
+            let step = self.done;
+
+            if let Some((from, to)) = self.moved_paths(step) {
+                settle_move(&from, &to)?;
+            }
+
+            match self.header.operations.get(step) {
+                // the copy may be partial, take it again
+                Some(Operation::Copy { to, .. }) if self.step_applied(step) => {
+                    remove_entry(
+                        &self
+                            .header
+                            .root
+                            .join(to.trim_end_matches('/')),
+                    )?;
+                }
+                Some(_) if self.step_applied(step) => {
+                    self.record(Record::Done { step })?;
+                    self.done = step + 1;
+                }
+                _ => {}
+            }
+
+            self.run()?;
+        }
+
+        self.commit()
+    }
+
+    pub fn revert(mut self) -> anyhow::Result<()> {
+        if self.committed {
+            anyhow::bail!(
+                "session {} was already committed, it can only be finished",
+                self.header.id
+            );
+        }
+
+        if let Some((from, to)) = self.moved_paths(self.done) {
+            settle_move(&from, &to)?;
+        }
+
+        if self.done < self.header.operations.len()
+            && self.step_applied(self.done)
+        {
+            self.done += 1;
+        }
+
+        while self.done > 0 {
+            let step = self.done - 1;
+
+            // reverting moves the entry back
+            if let Some((from, to)) = self.moved_paths(step) {
+                settle_move(&to, &from)?;
+            }
+
+            // the revert may already have happened right before a crash
+            if self.step_applied(step) {
+                self.revert_step(step)?;
+            }
+
+            self.record(Record::Reverted { step })?;
+            self.done = step;
+        }
+
+        for staging in self.stagings() {
+            let staging = self.header.root.join(staging);
+            if staging.exists() {
+                fs::remove_dir(staging)?;
+            }
+        }
+
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+// Shows copies across filesystems while they run and keeps the finished
+// ones for the report
+fn show_progress(slow_moves: &mut Vec<SlowMove>) -> impl FnMut(Progress) + '_ {
+    let mut shown = None;
+
+    move |progress| match progress {
+        Progress::Started { from, to, total } => {
+            eprintln!(
+                "{} is on another filesystem than {}, copying {total} bytes",
+                to.display(),
+                from.display()
+            );
+            shown = None;
+        }
+        Progress::Copied { bytes, total } => {
+            let percent = bytes * 100 / total.max(1);
+            if shown != Some(percent) {
+                eprint!("\r  {percent}%");
+                shown = Some(percent);
+            }
+        }
+        Progress::Finished(slow_move) => {
+            if shown.is_some() {
+                eprintln!();
+            }
+            slow_moves.push(slow_move.clone());
+        }
+    }
+}
+
+//
+// ============================
+// Apply
+// ============================
+//
+
+pub fn apply(
+    root: &Path,
+    roots: &[String],
+    operations: &[Operation],
+    delete: DeleteMode,
+) -> anyhow::Result<Applied> {
+    let root = root.canonicalize()?;
+
+    let mut journal = Journal::create(&root, roots, operations, delete)?;
+    let journal_path = journal.path.clone();
+
+    if let Err(error) = journal.run() {
+        match journal.revert() {
+            Ok(()) => {
+                anyhow::bail!("{error:#}\nAll changes were rolled back")
+            }
+            Err(revert_error) => anyhow::bail!(
+                "{error:#}\nRolling back failed as well ({revert_error:#}), \
+                 the journal is kept at {}; run `helix-move recover`",
+                journal_path.display()
+            ),
+        }
+    }
+
+    journal.commit()
+}
+
+// Sessions over this directory, or over one that contains it or is inside
+// of it
+pub fn pending_for(root: &Path) -> anyhow::Result<Option<Journal>> {
+    let root = root.canonicalize()?;
+
+    Ok(Journal::list()?
+        .into_iter()
+        .find(|journal| {
+            journal.header.root.starts_with(&root)
+                || root.starts_with(&journal.header.root)
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::isolated;
+
+    fn rename(from: &str, to: &str) -> Operation {
+        Operation::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn remove(path: &str) -> Operation {
+        Operation::Remove {
+            path: path.to_string(),
+        }
+    }
+
+    fn listing(root: &Path) -> Vec<String> {
+        crate::roots::collect_roots(root, &[]).unwrap()
+    }
+
+    fn sample_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("d")).unwrap();
+        fs::write(root.path().join("a"), "a").unwrap();
+        fs::write(root.path().join("b"), "b").unwrap();
+        fs::write(root.path().join("d/f"), "f").unwrap();
+        root
+    }
+
+    #[test]
+    fn applies_and_records_a_session() {
+        let (_guard, _state) = isolated();
+        let root = sample_root();
+
+        let operations = [
+            rename("b", ".b.helix-move-0"),
+            rename("a", "b"),
+            rename(".b.helix-move-0", "a"),
+            remove("d/"),
+        ];
+        let applied =
+            apply(root.path(), &[], &operations, DeleteMode::Permanent)
+                .unwrap();
+
+        assert_eq!(listing(root.path()), ["a", "b"]);
+        assert_eq!(fs::read_to_string(root.path().join("a")).unwrap(), "b");
+        assert!(Journal::list().unwrap().is_empty());
+
+        let sessions = Session::list().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, applied.id);
+        assert!(sessions[0].deleted[0].stored.is_none());
+    }
+
+    #[test]
+    fn failing_steps_roll_back_the_session() {
+        let (_guard, _state) = isolated();
+        let root = sample_root();
+        let before = listing(root.path());
+
+        let operations = [rename("a", "c"), remove("d/"), rename("gone", "x")];
+        let Err(error) =
+            apply(root.path(), &[], &operations, DeleteMode::Permanent)
+        else {
+            panic!("renaming a missing entry succeeded");
+        };
+
+        assert!(
+            error
+                .to_string()
+                .contains("rolled back"),
+            "{error:#}"
+        );
+        assert_eq!(listing(root.path()), before);
+        assert!(Journal::list().unwrap().is_empty());
+        assert!(Session::list().unwrap().is_empty());
+    }
+
+    // A crash after the second step ran, before it was recorded
+    fn interrupted(root: &Path) -> Journal {
+        let operations = [rename("a", "c"), remove("d/"), rename("b", "e")];
+        let mut journal = Journal::create(
+            &root.canonicalize().unwrap(),
+            &[],
+            &operations,
+            DeleteMode::Permanent,
+        )
+        .unwrap();
+
+        let mut links = HardLinks::default();
+        journal
+            .run_step(0, &mut links, &mut |_| {})
+            .unwrap();
+        journal
+            .record(Record::Done { step: 0 })
+            .unwrap();
+        journal
+            .run_step(1, &mut links, &mut |_| {})
+            .unwrap();
+
+        let path = journal.path.clone();
+        drop(journal);
+        Journal::open(&path).unwrap()
+    }
+
+    #[test]
+    fn recovering_finishes_an_interrupted_session() {
+        let (_guard, _state) = isolated();
+        let root = sample_root();
+
+        let journal = interrupted(root.path());
+        assert_eq!(journal.done, 1);
+        assert!(
+            pending_for(root.path())
+                .unwrap()
+                .is_some()
+        );
+
+        journal.finish().unwrap();
+
+        assert_eq!(listing(root.path()), ["c", "e"]);
+        assert!(
+            pending_for(root.path())
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(Session::list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn recovering_reverts_an_interrupted_session() {
+        let (_guard, _state) = isolated();
+        let root = sample_root();
+        let before = listing(root.path());
+
+        interrupted(root.path())
+            .revert()
+            .unwrap();
+
+        assert_eq!(listing(root.path()), before);
+        assert_eq!(fs::read_to_string(root.path().join("d/f")).unwrap(), "f");
+        assert!(
+            pending_for(root.path())
+                .unwrap()
+                .is_none()
+        );
+        assert!(Session::list().unwrap().is_empty());
+    }
+
+    // A crash while moving `d/` to `e/` across filesystems, with the copy
+    // still partial or already complete and the source partly removed
+    fn interrupted_move(root: &Path, copied: bool) -> Journal {
+        let journal = Journal::create(
+            &root.canonicalize().unwrap(),
+            &[],
+            &[rename("d/", "e/")],
+            DeleteMode::Permanent,
+        )
+        .unwrap();
+
+        let copy = match copied {
+            true => root.join("e"),
+            false => helix_move_lib::partial_path(&root.join("e")),
+        };
+        fs::create_dir(&copy).unwrap();
+        fs::write(copy.join("f"), "f").unwrap();
+        if copied {
+            fs::remove_file(root.join("d/f")).unwrap();
+        }
+
+        journal
+    }
+
+    #[test]
+    fn recovering_settles_interrupted_moves() {
+        let (_guard, _state) = isolated();
+
+        for copied in [false, true] {
+            let root = sample_root();
+            interrupted_move(root.path(), copied)
+                .finish()
+                .unwrap();
+
+            assert_eq!(listing(root.path()), ["a", "b", "e/", "e/f"]);
+
+            let root = sample_root();
+            interrupted_move(root.path(), copied)
+                .revert()
+                .unwrap();
+
+            assert_eq!(listing(root.path()), ["a", "b", "d/", "d/f"]);
+            assert_eq!(
+                fs::read_to_string(root.path().join("d/f")).unwrap(),
+                "f"
+            );
+        }
+    }
+}
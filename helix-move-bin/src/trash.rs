@@ -0,0 +1,168 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use helix_move_lib::{MetadataLoss, Progress, format_timestamp, move_entry};
+
+use crate::state::now;
+
+//
+// ============================
+// Freedesktop trash
+// ============================
+//
+// https://specifications.freedesktop.org/trash-spec/latest/
+//
+// Only the home trash is used, entries on other filesystems are copied
+// there by `move_entry`.
+//
+
+fn trash_dir() -> anyhow::Result<PathBuf> {
+    let base = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").ok_or(anyhow::anyhow!(
+                "Neither XDG_DATA_HOME nor HOME is set"
+            ))?;
+            PathBuf::from(home).join(".local/share")
+        }
+    };
+
+    Ok(base.join("Trash"))
+}
+
+// Moves `entry`, which used to live at `original`, to the trash and
+// returns where it ended up
+pub fn trash(
+    entry: &Path,
+    original: &Path,
+    losses: &mut Vec<MetadataLoss>,
+    progress: &mut dyn FnMut(Progress),
+) -> anyhow::Result<PathBuf> {
+    let dir = trash_dir()?;
+    let files = dir.join("files");
+    let info = dir.join("info");
+
+    let mut builder = DirBuilder::new();
+    builder.recursive(true).mode(0o700);
+    builder.create(&files)?;
+    builder.create(&info)?;
+
+    let name = original
+        .file_name()
+        .ok_or(anyhow::anyhow!("{} has no name", original.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let date = deletion_date(now());
+
+    for n in 1.. {
+        let candidate = match n {
+            1 => name.clone(),
+            n => format!("{name}.{n}"),
+        };
+
+        // creating the info file atomically reserves the name
+        let info_path = info.join(format!("{candidate}.trashinfo"));
+        let mut info_file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let stored = files.join(&candidate);
+        if fs::symlink_metadata(&stored).is_ok() {
+            drop(info_file);
+            fs::remove_file(&info_path)?;
+            continue;
+        }
+
+        let result = (|| -> anyhow::Result<()> {
+            writeln!(
+                info_file,
+                "[Trash Info]\nPath={}\nDeletionDate={date}",
+                encode_path(original)
+            )?;
+            info_file.sync_all()?;
+            losses.extend(move_entry(entry, &stored, progress)?);
+            Ok(())
+        })();
+
+        if let Err(error) = result {
+            let _ = fs::remove_file(&info_path);
+            return Err(error);
+        }
+
+        return Ok(stored);
+    }
+
+    unreachable!("ran out of trash names")
+}
+
+// Drops the info file of an entry that was restored from the trash
+pub fn forget(stored: &Path) -> anyhow::Result<()> {
+    let (Some(files), Some(name)) = (stored.parent(), stored.file_name())
+    else {
+        return Ok(());
+    };
+
+    if files
+        .file_name()
+        .is_none_or(|n| n != "files")
+    {
+        return Ok(());
+    }
+
+    let mut info_name = name.to_os_string();
+    info_name.push(".trashinfo");
+
+    let info_path = files
+        .with_file_name("info")
+        .join(info_name);
+    match fs::remove_file(info_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// The spec's deletion date is local time, as YYYY-MM-DDThh:mm:ss
+fn deletion_date(secs: u64) -> String {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return format_timestamp(secs)
+            .trim_end_matches(" UTC")
+            .replacen(' ', "T", 1);
+    }
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+// Percent-encodes everything but unreserved characters and '/'
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,780 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
+    fmt::Display,
+    fs::{self, File, Metadata},
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::Error;
+
+// Copies made so far of entries with several hard links. Copying another
+// link to the same inode links it to the first copy instead, as long as
+// both copies end up on the same filesystem.
+#[derive(Debug, Default)]
+pub struct HardLinks {
+    copies: HashMap<InodeKey, PathBuf>,
+}
+
+// the mtime guards against inode numbers being reused once all links to
+// an inode are gone
+type InodeKey = (u64, u64, i64, i64);
+
+impl HardLinks {
+    // the other links may be gone by the time an entry is looked up, only
+    // recording needs them
+    fn key(metadata: &Metadata) -> Option<InodeKey> {
+        (!metadata.is_dir()).then(|| {
+            (
+                metadata.dev(),
+                metadata.ino(),
+                metadata.mtime(),
+                metadata.mtime_nsec(),
+            )
+        })
+    }
+
+    fn first_copy(&self, metadata: &Metadata) -> Option<&Path> {
+        self.copies
+            .get(&Self::key(metadata)?)
+            .map(PathBuf::as_path)
+    }
+
+    fn record(&mut self, metadata: &Metadata, copy: &Path) {
+        if metadata.nlink() > 1
+            && let Some(key) = Self::key(metadata)
+        {
+            self.copies
+                .insert(key, copy.to_path_buf());
+        }
+    }
+
+    // Copies recorded within `from` are now found within `to`
+    fn moved(&mut self, from: &Path, to: &Path) {
+        for copy in self.copies.values_mut() {
+            if let Ok(rest) = copy.strip_prefix(from) {
+                *copy = to.join(rest);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl EntryKind {
+    pub fn of(file_type: fs::FileType) -> EntryKind {
+        if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_fifo() {
+            EntryKind::Fifo
+        } else if file_type.is_socket() {
+            EntryKind::Socket
+        } else if file_type.is_char_device() {
+            EntryKind::CharDevice
+        } else if file_type.is_block_device() {
+            EntryKind::BlockDevice
+        } else {
+            EntryKind::File
+        }
+    }
+
+    // Entries without contents, reading them blocks or fails
+    pub fn is_special(self) -> bool {
+        !matches!(self, EntryKind::File | EntryKind::Dir | EntryKind::Symlink)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attribute {
+    Mode,
+    Times,
+    Ownership,
+    Xattrs,
+}
+
+// Metadata that got lost while copying an entry
+#[derive(Debug)]
+pub struct MetadataLoss {
+    pub path: PathBuf,
+    pub attribute: Attribute,
+    pub source: io::Error,
+}
+
+// A move between filesystems, done as a verified copy and a removal
+#[derive(Debug, Clone)]
+pub struct SlowMove {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub bytes: u64,
+}
+
+pub enum Progress<'a> {
+    /// Renaming crossed filesystems, `total` bytes are copied instead
+    Started {
+        from: &'a Path,
+        to: &'a Path,
+        total: u64,
+    },
+    Copied {
+        bytes: u64,
+        total: u64,
+    },
+    /// The copy was verified and the source removed
+    Finished(&'a SlowMove),
+}
+
+impl Display for EntryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryKind::File => write!(f, "file"),
+            EntryKind::Dir => write!(f, "directory"),
+            EntryKind::Symlink => write!(f, "symlink"),
+            EntryKind::Fifo => write!(f, "fifo"),
+            EntryKind::Socket => write!(f, "socket"),
+            EntryKind::CharDevice => write!(f, "character device"),
+            EntryKind::BlockDevice => write!(f, "block device"),
+        }
+    }
+}
+
+impl Display for Attribute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Attribute::Mode => write!(f, "mode"),
+            Attribute::Times => write!(f, "timestamps"),
+            Attribute::Ownership => write!(f, "ownership"),
+            Attribute::Xattrs => write!(f, "extended attributes"),
+        }
+    }
+}
+
+impl Display for SlowMove {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} -> {} ({} bytes)",
+            self.from.display(),
+            self.to.display(),
+            self.bytes
+        )
+    }
+}
+
+impl Display for MetadataLoss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} not preserved ({})",
+            self.path.display(),
+            self.attribute,
+            self.source
+        )
+    }
+}
+
+// Recursively copies `from` to `to`, which must not exist yet. Hard links
+// within the copied tree stay linked.
+pub fn copy_entry(from: &Path, to: &Path) -> Result<Vec<MetadataLoss>, Error> {
+    let mut losses = Vec::new();
+    copy_into(
+        from,
+        to,
+        &mut losses,
+        &mut HardLinks::default(),
+        &mut |_| {},
+    )?;
+    Ok(losses)
+}
+
+// `copied` is called with the size of every chunk of file contents
+fn copy_into(
+    from: &Path,
+    to: &Path,
+    losses: &mut Vec<MetadataLoss>,
+    links: &mut HardLinks,
+    copied: &mut dyn FnMut(u64),
+) -> Result<(), Error> {
+    let metadata = fs::symlink_metadata(from).map_err(Error::io(from))?;
+    let file_type = metadata.file_type();
+
+    // the metadata came along with the first copy, linking fails when that
+    // one is gone or on another filesystem
+    if let Some(first) = links.first_copy(&metadata)
+        && fs::hard_link(first, to).is_ok()
+    {
+        return Ok(());
+    }
+
+    if file_type.is_dir() {
+        fs::create_dir(to).map_err(Error::io(to))?;
+
+        for entry in fs::read_dir(from).map_err(Error::io(from))? {
+            let entry = entry.map_err(Error::io(from))?;
+            copy_into(
+                &entry.path(),
+                &to.join(entry.file_name()),
+                losses,
+                links,
+                copied,
+            )?;
+        }
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(from).map_err(Error::io(from))?;
+        std::os::unix::fs::symlink(target, to).map_err(Error::io(to))?;
+    } else if EntryKind::of(file_type).is_special() {
+        // opening a fifo would block, recreate the node instead
+        make_node(&metadata, to).map_err(Error::io(to))?;
+    } else {
+        copy_contents(from, to, copied)?;
+    }
+
+    // after the children, adding them would bump the times again
+    copy_metadata(&metadata, from, to, losses);
+    links.record(&metadata, to);
+    Ok(())
+}
+
+// Copies a file chunk by chunk, its mode follows with the rest of the
+// metadata
+fn copy_contents(
+    from: &Path,
+    to: &Path,
+    copied: &mut dyn FnMut(u64),
+) -> Result<(), Error> {
+    let mut source = File::open(from).map_err(Error::io(from))?;
+    let mut target = File::create_new(to).map_err(Error::io(to))?;
+
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = source
+            .read(&mut buf)
+            .map_err(Error::io(from))?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        target
+            .write_all(&buf[..read])
+            .map_err(Error::io(to))?;
+        copied(read as u64);
+    }
+}
+
+// Renames, falling back to a verified copy and a removal across
+// filesystems
+pub fn move_entry(
+    from: &Path,
+    to: &Path,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Vec<MetadataLoss>, Error> {
+    move_entry_linked(from, to, &mut HardLinks::default(), progress)
+}
+
+// Like `move_entry`, keeping entries copied across filesystems linked to
+// the earlier copies of their inode in `links`
+pub fn move_entry_linked(
+    from: &Path,
+    to: &Path,
+    links: &mut HardLinks,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<Vec<MetadataLoss>, Error> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(Vec::new()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let total = total_size(from, links, &mut HashSet::new())?;
+            progress(Progress::Started { from, to, total });
+
+            // the copy only takes its name once it is complete and verified
+            let partial = partial_path(to);
+            let mut losses = Vec::new();
+            let mut bytes = 0;
+            let copy =
+                copy_into(from, &partial, &mut losses, links, &mut |size| {
+                    bytes += size;
+                    progress(Progress::Copied { bytes, total });
+                })
+                .and_then(|()| verify_copy(from, &partial));
+
+            // the source is only removed once the copy is known to be good
+            if let Err(error) = copy {
+                if fs::symlink_metadata(&partial).is_ok() {
+                    remove_entry(&partial)?;
+                }
+                return Err(error);
+            }
+
+            fs::rename(&partial, to).map_err(Error::io(to))?;
+            links.moved(&partial, to);
+            remove_entry(from)?;
+
+            progress(Progress::Finished(&SlowMove {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+                bytes,
+            }));
+            Ok(losses)
+        }
+        Err(e) => Err(Error::io(from)(e)),
+    }
+}
+
+// Where a move across filesystems copies to before the copy is complete
+pub fn partial_path(to: &Path) -> PathBuf {
+    let name = to
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    to.with_file_name(format!(".{name}.helix-move-partial"))
+}
+
+// Settles a move from `from` to the then free `to` that was interrupted by
+// a crash: a partial copy is dropped, a source that was partly removed
+// after its copy was complete goes away. The move has then either not
+// started or is done.
+pub fn settle_move(from: &Path, to: &Path) -> Result<(), Error> {
+    let exists = |path: &Path| fs::symlink_metadata(path).is_ok();
+    let partial = partial_path(to);
+
+    if exists(&partial) {
+        remove_entry(&partial)?;
+    }
+
+    if exists(from) && exists(to) {
+        remove_entry(from)?;
+    }
+
+    Ok(())
+}
+
+// Bytes to copy, every inode counts once
+fn total_size(
+    path: &Path,
+    links: &HardLinks,
+    seen: &mut HashSet<InodeKey>,
+) -> Result<u64, Error> {
+    let metadata = fs::symlink_metadata(path).map_err(Error::io(path))?;
+
+    if !metadata.is_dir() {
+        let linked = HardLinks::key(&metadata).is_some_and(|key| {
+            links.copies.contains_key(&key) || !seen.insert(key)
+        });
+
+        return Ok(if metadata.is_file() && !linked {
+            metadata.len()
+        } else {
+            0
+        });
+    }
+
+    let mut total = 0;
+    for entry in fs::read_dir(path).map_err(Error::io(path))? {
+        let entry = entry.map_err(Error::io(path))?;
+        total += total_size(&entry.path(), links, seen)?;
+    }
+
+    Ok(total)
+}
+
+// Compares the copy with its original: kinds, link targets, device
+// numbers, directory entries and file contents
+fn verify_copy(from: &Path, to: &Path) -> Result<(), Error> {
+    let mismatch = || Error::CopyMismatch {
+        path: to.to_path_buf(),
+    };
+
+    let original = fs::symlink_metadata(from).map_err(Error::io(from))?;
+    let copy = fs::symlink_metadata(to).map_err(Error::io(to))?;
+
+    if original.file_type() != copy.file_type() {
+        return Err(mismatch());
+    }
+
+    if original.is_dir() {
+        let names = |dir: &Path| -> Result<Vec<_>, Error> {
+            let mut names = fs::read_dir(dir)
+                .map_err(Error::io(dir))?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<io::Result<Vec<_>>>()
+                .map_err(Error::io(dir))?;
+            names.sort();
+            Ok(names)
+        };
+
+        let original_names = names(from)?;
+        if original_names != names(to)? {
+            return Err(mismatch());
+        }
+
+        for name in original_names {
+            verify_copy(&from.join(&name), &to.join(&name))?;
+        }
+    } else if original.is_symlink() {
+        if fs::read_link(from).map_err(Error::io(from))?
+            != fs::read_link(to).map_err(Error::io(to))?
+        {
+            return Err(mismatch());
+        }
+    } else if EntryKind::of(original.file_type()).is_special() {
+        if original.rdev() != copy.rdev() {
+            return Err(mismatch());
+        }
+    } else if original.is_file()
+        && (original.len() != copy.len() || !same_contents(from, to)?)
+    {
+        return Err(mismatch());
+    }
+
+    Ok(())
+}
+
+fn same_contents(a: &Path, b: &Path) -> Result<bool, Error> {
+    let mut a_file = File::open(a).map_err(Error::io(a))?;
+    let mut b_file = File::open(b).map_err(Error::io(b))?;
+
+    let mut a_buf = vec![0u8; 64 * 1024];
+    let mut b_buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = a_file
+            .read(&mut a_buf)
+            .map_err(Error::io(a))?;
+        if read == 0 {
+            // the copy must end here as well
+            return Ok(b_file
+                .read(&mut b_buf)
+                .map_err(Error::io(b))?
+                == 0);
+        }
+
+        b_file
+            .read_exact(&mut b_buf[..read])
+            .map_err(Error::io(b))?;
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+pub fn remove_entry(path: &Path) -> Result<(), Error> {
+    let metadata = fs::symlink_metadata(path).map_err(Error::io(path))?;
+
+    if metadata.is_dir() {
+        fs::remove_dir_all(path).map_err(Error::io(path))
+    } else {
+        fs::remove_file(path).map_err(Error::io(path))
+    }
+}
+
+//
+// ---- Metadata ----
+//
+
+// Copies ownership, xattrs, mode and times of `from` (described by
+// `metadata`) onto `to`, in that order: chown may clear setuid bits and
+// every other change touches ctime/mtime.
+pub fn copy_metadata(
+    metadata: &Metadata,
+    from: &Path,
+    to: &Path,
+    losses: &mut Vec<MetadataLoss>,
+) {
+    let mut lost = |attribute, result: io::Result<()>| {
+        if let Err(source) = result {
+            losses.push(MetadataLoss {
+                path: to.to_path_buf(),
+                attribute,
+                source,
+            });
+        }
+    };
+
+    lost(Attribute::Ownership, copy_ownership(metadata, to));
+    lost(Attribute::Xattrs, copy_xattrs(from, to));
+
+    // symlinks have no mode of their own on Linux
+    if !metadata.file_type().is_symlink() {
+        lost(
+            Attribute::Mode,
+            fs::set_permissions(
+                to,
+                fs::Permissions::from_mode(metadata.mode() & 0o7777),
+            ),
+        );
+    }
+
+    lost(Attribute::Times, copy_times(metadata, to));
+}
+
+fn copy_ownership(metadata: &Metadata, to: &Path) -> io::Result<()> {
+    let current = fs::symlink_metadata(to)?;
+
+    // only root may give files away, don't fail when there is nothing to do
+    if current.uid() == metadata.uid() && current.gid() == metadata.gid() {
+        return Ok(());
+    }
+
+    std::os::unix::fs::lchown(to, Some(metadata.uid()), Some(metadata.gid()))
+}
+
+fn copy_times(metadata: &Metadata, to: &Path) -> io::Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+
+    let path = c_path(to.as_os_str())?;
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn make_node(metadata: &Metadata, to: &Path) -> io::Result<()> {
+    let path = c_path(to.as_os_str())?;
+    let result = unsafe {
+        libc::mknod(
+            path.as_ptr(),
+            metadata.mode() as libc::mode_t,
+            metadata.rdev() as libc::dev_t,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    let from_c = c_path(from.as_os_str())?;
+    let to_c = c_path(to.as_os_str())?;
+
+    let names = match read_xattr(|buf, len| unsafe {
+        libc::llistxattr(from_c.as_ptr(), buf, len)
+    }) {
+        Ok(names) => names,
+        // nothing to preserve on filesystems without xattrs
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut first_error = None;
+
+    for name in names
+        .split(|&b| b == 0)
+        .filter(|n| !n.is_empty())
+    {
+        let name = c_path(OsStr::from_bytes(name))?;
+
+        let result = read_xattr(|buf, len| unsafe {
+            libc::lgetxattr(from_c.as_ptr(), name.as_ptr(), buf.cast(), len)
+        })
+        .and_then(|value| {
+            let result = unsafe {
+                libc::lsetxattr(
+                    to_c.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            };
+
+            match result {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        });
+
+        // keep copying the remaining attributes
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// Calls a size-then-fill style xattr function until the buffer fits
+fn read_xattr(
+    call: impl Fn(*mut libc::c_char, usize) -> libc::ssize_t,
+) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = call(buf.as_mut_ptr().cast(), buf.len());
+
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+
+        // the value grew in between
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+fn c_path(path: &OsStr) -> io::Result<CString> {
+    CString::new(path.as_bytes()).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a directory with a file linked twice, a symlink and a fifo
+    fn sample_tree(dir: &Path) -> PathBuf {
+        let tree = dir.join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("file"), vec![7u8; 200_000]).unwrap();
+        fs::set_permissions(
+            tree.join("file"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        fs::hard_link(tree.join("file"), tree.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink("../file", tree.join("sub/symlink"))
+            .unwrap();
+        let fifo = c_path(tree.join("fifo").as_os_str()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        tree
+    }
+
+    #[test]
+    fn copies_keep_links_kinds_and_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = sample_tree(dir.path());
+        let copy = dir.path().join("copy");
+
+        let losses = copy_entry(&tree, &copy).unwrap();
+        assert!(losses.is_empty(), "{losses:?}");
+        verify_copy(&tree, &copy).unwrap();
+
+        let file = fs::metadata(copy.join("file")).unwrap();
+        let link = fs::metadata(copy.join("sub/link")).unwrap();
+        assert_eq!(file.ino(), link.ino());
+        assert_ne!(
+            file.ino(),
+            fs::metadata(tree.join("file"))
+                .unwrap()
+                .ino()
+        );
+        assert_eq!(file.permissions().mode() & 0o777, 0o640);
+        assert_eq!(
+            fs::read_link(copy.join("sub/symlink")).unwrap(),
+            Path::new("../file")
+        );
+        assert!(
+            fs::symlink_metadata(copy.join("fifo"))
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+    }
+
+    #[test]
+    fn verification_catches_changed_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = sample_tree(dir.path());
+        let copy = dir.path().join("copy");
+        copy_entry(&tree, &copy).unwrap();
+
+        fs::write(copy.join("sub/link"), "changed").unwrap();
+        assert!(matches!(
+            verify_copy(&tree, &copy),
+            Err(Error::CopyMismatch { .. })
+        ));
+
+        fs::remove_file(copy.join("sub/link")).unwrap();
+        assert!(matches!(
+            verify_copy(&tree, &copy),
+            Err(Error::CopyMismatch { .. })
+        ));
+    }
+
+    // needs /dev/shm on another filesystem than the temporary directory
+    #[test]
+    fn moves_across_filesystems_copy_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let Ok(other) = tempfile::tempdir_in("/dev/shm") else {
+            return;
+        };
+        if fs::metadata(dir.path()).unwrap().dev()
+            == fs::metadata(other.path())
+                .unwrap()
+                .dev()
+        {
+            return;
+        }
+
+        let tree = sample_tree(dir.path());
+        let to = other.path().join("tree");
+
+        let mut chunks = 0;
+        let mut finished = None;
+        move_entry(&tree, &to, &mut |progress| match progress {
+            Progress::Copied { bytes, total } => {
+                chunks += 1;
+                assert!(bytes <= total);
+            }
+            Progress::Finished(slow_move) => finished = Some(slow_move.bytes),
+            Progress::Started { .. } => {}
+        })
+        .unwrap();
+
+        // the file counts once, its second link is linked again
+        assert_eq!(finished, Some(200_000));
+        assert!(chunks > 1);
+        assert!(fs::symlink_metadata(&tree).is_err());
+        assert_eq!(
+            fs::metadata(to.join("file"))
+                .unwrap()
+                .ino(),
+            fs::metadata(to.join("sub/link"))
+                .unwrap()
+                .ino()
+        );
+    }
+}
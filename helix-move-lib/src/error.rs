@@ -0,0 +1,72 @@
+use std::{fmt::Display, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    MissingSource { path: String },
+    KindMismatch { from: String, to: String },
+    DestinationExists { path: String },
+    MissingParent { path: String },
+    LengthMismatch { original: usize, new: usize },
+    LinkChanged { path: String, target: String },
+    UnknownId { id: usize },
+    NothingToCopy { line: usize },
+    CopyMismatch { path: PathBuf },
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingSource { path } => {
+                write!(f, "{path} does not exist")
+            }
+            Error::KindMismatch { from, to } => write!(
+                f,
+                "{from} and {to} disagree on being a directory (trailing '/')"
+            ),
+            Error::DestinationExists { path } => {
+                write!(f, "{path} already exists")
+            }
+            Error::MissingParent { path } => {
+                write!(f, "parent directory of {path} does not exist")
+            }
+            Error::LengthMismatch { original, new } => write!(
+                f,
+                "expected {original} lines but the edited list has {new}"
+            ),
+            Error::LinkChanged { path, target } => {
+                write!(f, "{path} is no longer a link to {target}")
+            }
+            Error::UnknownId { id } => {
+                write!(f, "line id {id} does not belong to any entry")
+            }
+            Error::NothingToCopy { line } => {
+                write!(f, "line {line} copies an entry but none is above it")
+            }
+            Error::CopyMismatch { path } => write!(
+                f,
+                "{}: copy differs from the original, which was kept",
+                path.display()
+            ),
+            Error::Io { path, source } => {
+                write!(f, "{}: {source}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
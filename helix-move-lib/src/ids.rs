@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use crate::{Error, LineRules, Rule, created_path};
+
+//
+// -----------------------------
+// Phase 1 — Build Rules (by id)
+// -----------------------------
+//
+// Every line starts with the id of its original entry and a tab, like
+// vidir. Lines can be reordered freely: removed ids are deletions, lines
+// without an id (with or without "+ ") are new entries and ids used more
+// than once are copies.
+//
+
+pub fn format_with_ids(entries: &[String]) -> Vec<String> {
+    let width = entries.len().to_string().len();
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| format!("{:0width$}\t{entry}", i + 1))
+        .collect()
+}
+
+// Splits "{id}\t{path}", lines without a numeric id have none
+pub fn parse_id_line(line: &str) -> (Option<usize>, &str) {
+    if let Some((id, path)) = line.split_once('\t')
+        && let Ok(id) = id.parse::<usize>()
+    {
+        return (Some(id), path);
+    }
+
+    (None, line)
+}
+
+pub fn build_rules_by_id(
+    original: &[String],
+    new: &[String],
+) -> Result<LineRules, Error> {
+    let mut by_id: HashMap<usize, Vec<(usize, &str)>> = HashMap::new();
+    let mut created: Vec<(usize, &str)> = Vec::new();
+
+    for (line, text) in new.iter().enumerate() {
+        // a deleted line without its id is just as gone
+        if text.trim().is_empty() || text.starts_with("- ") {
+            continue;
+        }
+
+        // "+ " is optional here, every line without an id is new
+        if let Some(path) = created_path(text) {
+            created.push((line, path));
+            continue;
+        }
+
+        match parse_id_line(text) {
+            (Some(id), path) => {
+                if id == 0 || id > original.len() {
+                    return Err(Error::UnknownId { id });
+                }
+
+                by_id
+                    .entry(id)
+                    .or_default()
+                    .push((line, path));
+            }
+            (None, path) => created.push((line, path)),
+        }
+    }
+
+    let mut rules = Vec::new();
+    let mut lines = Vec::new();
+    let mut copies = Vec::new();
+
+    for (i, old) in original.iter().enumerate() {
+        let Some(occurrences) = by_id.get(&(i + 1)) else {
+            rules.push(Rule::Deleted { path: old.clone() });
+            lines.push(None);
+            continue;
+        };
+
+        // the untouched occurrence keeps the entry, the others copy it
+        let keep = occurrences
+            .iter()
+            .position(|&(_, new)| new == old)
+            .unwrap_or(0);
+
+        for (j, &(line, new)) in occurrences.iter().enumerate() {
+            if j != keep {
+                copies.push((line, old, new));
+            }
+        }
+
+        let (line, new) = occurrences[keep];
+
+        rules.push(if new == old {
+            Rule::Unchanged { path: old.clone() }
+        } else if new.starts_with("- ") && !old.starts_with("- ") {
+            Rule::Deleted { path: old.clone() }
+        } else {
+            Rule::Renamed {
+                from: old.clone(),
+                to: new.to_string(),
+            }
+        });
+        lines.push(Some(line));
+    }
+
+    for (line, from, to) in copies {
+        rules.push(Rule::Copied {
+            from: from.clone(),
+            to: to.to_string(),
+        });
+        lines.push(Some(line));
+    }
+
+    for (line, path) in created {
+        rules.push(Rule::Created {
+            path: path.to_string(),
+        });
+        lines.push(Some(line));
+    }
+
+    Ok(LineRules { rules, lines })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ids_are_padded_to_the_same_width() {
+        let entries: Vec<String> = (0..10)
+            .map(|i| format!("f{i}"))
+            .collect();
+        let formatted = format_with_ids(&entries);
+
+        assert_eq!(formatted[0], "01\tf0");
+        assert_eq!(formatted[9], "10\tf9");
+        assert_eq!(parse_id_line("07\ta b"), (Some(7), "a b"));
+        assert_eq!(parse_id_line("new\tname"), (None, "new\tname"));
+    }
+
+    #[test]
+    fn pairs_lines_by_id_in_any_order() {
+        let original = lines(&["a", "b", "c", "d"]);
+        let built = build_rules_by_id(
+            &original,
+            &lines(&["3\tc2", "1\ta", "new", "1\ta copy", "+ plus", "4\t- d"]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            built.rules,
+            vec![
+                Rule::Unchanged {
+                    path: "a".to_string(),
+                },
+                Rule::Deleted {
+                    path: "b".to_string(),
+                },
+                Rule::Renamed {
+                    from: "c".to_string(),
+                    to: "c2".to_string(),
+                },
+                Rule::Deleted {
+                    path: "d".to_string(),
+                },
+                Rule::Copied {
+                    from: "a".to_string(),
+                    to: "a copy".to_string(),
+                },
+                Rule::Created {
+                    path: "new".to_string(),
+                },
+                Rule::Created {
+                    path: "plus".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            built.lines,
+            vec![Some(1), None, Some(0), Some(5), Some(3), Some(2), Some(4)]
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_ids() {
+        assert!(matches!(
+            build_rules_by_id(&lines(&["a"]), &lines(&["2\tb"])),
+            Err(Error::UnknownId { id: 2 })
+        ));
+    }
+}
@@ -1,15 +1,49 @@
-use std::{collections::BTreeSet, fmt::Display, fs, path::Path};
+use std::{collections::BTreeSet, fmt::Display};
+
+mod copy;
+mod error;
+mod ids;
+mod links;
+mod paths;
+mod plan;
+mod time;
+mod validate;
+
+pub use copy::*;
+pub use error::Error;
+pub use ids::*;
+pub use links::*;
+pub use paths::*;
+pub use plan::*;
+pub use time::*;
+pub use validate::*;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Rule {
-    Unchanged { path: String },
-    Renamed { from: String, to: String },
-    Deleted { path: String },
+    Unchanged {
+        path: String,
+    },
+    Renamed {
+        from: String,
+        to: String,
+    },
+    Deleted {
+        path: String,
+    },
+    /// A new entry that was not in the original list
+    Created {
+        path: String,
+    },
+    /// A duplicate of the original entry `from`, which is kept as well
+    Copied {
+        from: String,
+        to: String,
+    },
 }
 
-impl Into<FullRule> for &Rule {
-    fn into(self) -> FullRule {
-        match self {
+impl From<&Rule> for FullRule {
+    fn from(rule: &Rule) -> Self {
+        match rule {
             Rule::Unchanged { path } => FullRule::Unchanged {
                 path: path.to_string(),
             },
@@ -20,6 +54,13 @@ impl Into<FullRule> for &Rule {
             Rule::Deleted { path } => FullRule::Deleted {
                 path: path.to_string(),
             },
+            Rule::Created { path } => FullRule::Added {
+                path: path.to_string(),
+            },
+            Rule::Copied { from, to } => FullRule::Copied {
+                from: from.to_string(),
+                to: to.to_string(),
+            },
         }
     }
 }
@@ -30,24 +71,7 @@ pub enum FullRule {
     Renamed { from: String, to: String },
     Deleted { path: String },
     Added { path: String },
-}
-
-impl Into<Option<EditRule>> for &FullRule {
-    fn into(self) -> Option<EditRule> {
-        match self {
-            FullRule::Unchanged { path } => Some(EditRule::Unchanged {
-                path: path.to_string(),
-            }),
-            FullRule::Renamed { from, to } => Some(EditRule::Renamed {
-                from: from.to_string(),
-                to: to.to_string(),
-            }),
-            FullRule::Deleted { path: _ } => None,
-            FullRule::Added { path } => Some(EditRule::Added {
-                path: path.to_string(),
-            }),
-        }
-    }
+    Copied { from: String, to: String },
 }
 
 impl Display for FullRule {
@@ -57,85 +81,82 @@ impl Display for FullRule {
             FullRule::Renamed { from, to } => write!(f, "~ {from} -> {to}"),
             FullRule::Deleted { path } => write!(f, "- {path}"),
             FullRule::Added { path } => write!(f, "+ {path}"),
+            FullRule::Copied { from, to } => write!(f, "c {from} -> {to}"),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum EditRule {
-    Unchanged { path: String },
-    Renamed { from: String, to: String },
-    Added { path: String },
+//
+// -----------------------------
+// Phase 1 — Build Rules
+// -----------------------------
+//
+pub struct LineRules {
+    /// One rule per original entry in original order, then new entries
+    pub rules: Vec<Rule>,
+    /// Edited line of every rule, None for entries whose line was removed
+    pub lines: Vec<Option<usize>>,
 }
 
-impl EditRule {
-    pub fn apply(
-        &self,
-        old_root: &Path,
-        new_root: &Path,
-    ) -> anyhow::Result<()> {
-        let old_root = old_root.canonicalize()?;
-        let new_root = new_root.canonicalize()?;
-
-        match self {
-            EditRule::Unchanged { path: raw_path } => {
-                let old_path = old_root.join(raw_path);
-                let new_path = new_root.join(raw_path);
+// Path of a line that creates a new entry ("+ path")
+pub fn created_path(line: &str) -> Option<&str> {
+    line.strip_prefix("+ ")
+}
 
-                assert!(old_path.exists());
-                assert!(old_path.is_dir() == raw_path.ends_with("/"));
+// Path of a line that copies the entry above it ("c path")
+pub fn copied_path(line: &str) -> Option<&str> {
+    line.strip_prefix("c ")
+}
 
-                if old_path.is_dir() {
-                    fs::create_dir(new_path)?;
-                } else {
-                    fs::copy(old_path, new_path)?;
-                }
-            }
-            EditRule::Renamed {
-                from: raw_from,
-                to: raw_to,
-            } => {
-                let from = old_root.join(&raw_from);
-                let to = new_root.join(&raw_to);
-
-                assert!(from.exists());
-                assert!(raw_from.ends_with("/") == raw_to.ends_with("/"));
-                assert!(from.is_dir() == raw_from.ends_with("/"));
-
-                if from.is_dir() {
-                    fs::create_dir(to)?;
-                } else {
-                    fs::copy(from, to)?;
-                }
-            }
-            EditRule::Added { path: raw_path } => {
-                let new_path = new_root.join(&raw_path);
+// Which edited lines pair with an original entry by position. "+ " and
+// "c " lines don't, unless they are the original entry next in line, whose
+// name may start like that.
+pub fn paired_lines(original: &[String], edited: &[String]) -> Vec<bool> {
+    let mut next = 0;
 
-                assert!(raw_path.ends_with("/"));
+    edited
+        .iter()
+        .map(|line| {
+            let unpaired =
+                created_path(line).is_some() || copied_path(line).is_some();
+            let paired = !unpaired || original.get(next) == Some(line);
 
-                fs::create_dir(new_path)?;
+            if paired {
+                next += 1;
             }
-        }
+            paired
+        })
+        .collect()
+}
 
-        Ok(())
-    }
+pub fn build_rules(
+    original: &[String],
+    new: &[String],
+) -> Result<Vec<Rule>, Error> {
+    Ok(build_line_rules(original, new)?.rules)
 }
 
-//
-// -----------------------------
-// Phase 1 — Build Rules
-// -----------------------------
-//
-pub fn build_rules(original: &[String], new: &[String]) -> Vec<Rule> {
-    assert_eq!(
-        original.len(),
-        new.len(),
-        "original and new must have same length"
-    );
-
-    original
+// Pairs lines by position, "+ " lines are new entries and don't count
+pub fn build_line_rules(
+    original: &[String],
+    new: &[String],
+) -> Result<LineRules, Error> {
+    let mask = paired_lines(original, new);
+    let (paired, unpaired): (Vec<_>, Vec<_>) = new
         .iter()
-        .zip(new.iter())
+        .enumerate()
+        .partition(|(line, _)| mask[*line]);
+
+    if original.len() != paired.len() {
+        return Err(Error::LengthMismatch {
+            original: original.len(),
+            new: paired.len(),
+        });
+    }
+
+    let mut rules: Vec<Rule> = original
+        .iter()
+        .zip(paired.iter().map(|(_, new)| *new))
         .map(|(old, new)| {
             if old == new {
                 Rule::Unchanged { path: old.clone() }
@@ -148,7 +169,37 @@ pub fn build_rules(original: &[String], new: &[String]) -> Vec<Rule> {
                 }
             }
         })
-        .collect()
+        .collect();
+
+    let mut lines: Vec<Option<usize>> = paired
+        .iter()
+        .map(|(line, _)| Some(*line))
+        .collect();
+
+    for (line, text) in unpaired {
+        if let Some(path) = created_path(text) {
+            rules.push(Rule::Created {
+                path: path.to_string(),
+            });
+            lines.push(Some(line));
+        } else if let Some(path) = copied_path(text) {
+            // copies take the original entry of the closest line above
+            let Some(source) = paired
+                .iter()
+                .rposition(|(paired_line, _)| *paired_line < line)
+            else {
+                return Err(Error::NothingToCopy { line: line + 1 });
+            };
+
+            rules.push(Rule::Copied {
+                from: original[source].clone(),
+                to: path.to_string(),
+            });
+            lines.push(Some(line));
+        }
+    }
+
+    Ok(LineRules { rules, lines })
 }
 
 //
@@ -170,9 +221,23 @@ pub fn normalize_rules(rules: &[Rule]) -> Vec<Rule> {
         })
         .collect();
 
+    // entries explicitly renamed out of a deleted directory survive it
+    let rescue_paths: Vec<String> = rules
+        .iter()
+        .filter_map(|r| match r {
+            Rule::Renamed { from, to }
+                if is_deleted(from, &delete_paths, &[])
+                    && !is_deleted(to, &delete_paths, &[]) =>
+            {
+                Some(from.clone())
+            }
+            _ => None,
+        })
+        .collect();
+
     let stage1: Vec<Rule> = rules
         .iter()
-        .map(|rule| apply_deletes(rule, &delete_paths))
+        .map(|rule| apply_deletes(rule, &delete_paths, &rescue_paths))
         .collect();
 
     // ---- STEP 2: APPLY RENAMES ----
@@ -187,8 +252,8 @@ pub fn normalize_rules(rules: &[Rule]) -> Vec<Rule> {
         let current = normalized[i].clone();
 
         if let Rule::Renamed { from, to } = &current {
-            for j in (i + 1)..normalized.len() {
-                normalized[j] = rewrite_rule(&normalized[j], from, to);
+            for rule in normalized.iter_mut().skip(i + 1) {
+                *rule = rewrite_rule(rule, from, to);
             }
         }
     }
@@ -196,13 +261,50 @@ pub fn normalize_rules(rules: &[Rule]) -> Vec<Rule> {
     normalized
 }
 
+// Entries a delete or rename of the directory `path` cascades to, taken
+// from the normalized rules. Entries renamed elsewhere are not included.
+pub fn affected_entries<'a>(rules: &'a [Rule], path: &str) -> Vec<&'a Rule> {
+    if !path.ends_with('/') {
+        return Vec::new();
+    }
+
+    let inside = |p: &str| p != path && path_starts_with(p, path);
+
+    for rule in rules {
+        match rule {
+            Rule::Deleted { path: deleted } if deleted == path => {
+                return rules
+                    .iter()
+                    .filter(
+                        |r| matches!(r, Rule::Deleted { path } if inside(path)),
+                    )
+                    .collect();
+            }
+            Rule::Renamed { from, to } if from == path => {
+                return rules
+                    .iter()
+                    .filter(|r| match r {
+                        Rule::Renamed { from: f, to: t } => {
+                            inside(f) && *t == rewrite_path(f, from, to)
+                        }
+                        _ => false,
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    Vec::new()
+}
+
 //
 // -----------------------------
 // Phase 3 — Detect new directories
 // -----------------------------
 //
 
-pub fn add_missing_directories(rules: &[Rule]) -> Vec<FullRule> {
+pub fn add_missing_directories(rules: &[Rule]) -> Result<Vec<FullRule>, Error> {
     let mut full_rules = Vec::new();
 
     // 1️⃣ Collect existing destination paths
@@ -211,21 +313,50 @@ pub fn add_missing_directories(rules: &[Rule]) -> Vec<FullRule> {
         .filter_map(rule_dest_path)
         .collect();
 
+    // unlisted ancestors of listed entries, like the directories around
+    // the roots of a session, stay where they are
+    let listed: BTreeSet<&str> = rules
+        .iter()
+        .filter_map(|rule| match rule {
+            Rule::Unchanged { path } | Rule::Deleted { path } => Some(path),
+            Rule::Renamed { from, .. } => Some(from),
+            Rule::Created { .. } | Rule::Copied { .. } => None,
+        })
+        .map(String::as_str)
+        .collect();
+
+    for path in &listed {
+        let mut current = path.to_string();
+        while let Some(parent) = parent_dir(&current) {
+            if !listed.contains(parent.as_str()) {
+                existing.insert(parent.clone());
+            }
+            current = parent;
+        }
+    }
+
+    let mut claimed: BTreeSet<String> = BTreeSet::new();
+
     for rule in rules {
+        if let Some(dest) = rule_dest_path(rule)
+            && !claimed.insert(dest.clone())
+        {
+            return Err(Error::DestinationExists { path: dest });
+        }
+
         match rule {
             Rule::Renamed { from, to } => {
-                // 2️⃣ Compute missing parents
-                let missing = get_missing_parents(to, &existing);
-
-                // 3️⃣ Add them first
-                for parent in missing {
-                    full_rules.push(FullRule::Added {
-                        path: parent.clone(),
+                if from.ends_with('/') != to.ends_with('/') {
+                    return Err(Error::KindMismatch {
+                        from: from.clone(),
+                        to: to.clone(),
                     });
-                    existing.insert(parent);
                 }
 
-                // 4️⃣ Push rename
+                // 2️⃣ Add missing parents first
+                add_missing_parents(to, &mut existing, &mut full_rules)?;
+
+                // 3️⃣ Push rename
                 full_rules.push(FullRule::Renamed {
                     from: from.clone(),
                     to: to.clone(),
@@ -242,33 +373,109 @@ pub fn add_missing_directories(rules: &[Rule]) -> Vec<FullRule> {
             Rule::Deleted { path } => {
                 full_rules.push(FullRule::Deleted { path: path.clone() });
             }
+
+            Rule::Created { path } => {
+                add_missing_parents(path, &mut existing, &mut full_rules)?;
+
+                full_rules.push(FullRule::Added { path: path.clone() });
+                existing.insert(path.clone());
+            }
+
+            Rule::Copied { from, to } => {
+                if from.ends_with('/') != to.ends_with('/') {
+                    return Err(Error::KindMismatch {
+                        from: from.clone(),
+                        to: to.clone(),
+                    });
+                }
+
+                add_missing_parents(to, &mut existing, &mut full_rules)?;
+
+                full_rules.push(FullRule::Copied {
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+                existing.insert(to.clone());
+            }
         }
     }
 
-    full_rules
+    Ok(full_rules)
 }
 
-pub fn filter_full_rules(rules: &[FullRule]) -> Vec<FullRule> {
-    rules
-        .iter()
-        .filter(|r| !matches!(r, FullRule::Unchanged { .. }))
-        .map(|r| r.clone())
-        .collect()
+fn add_missing_parents(
+    path: &str,
+    existing: &mut BTreeSet<String>,
+    full_rules: &mut Vec<FullRule>,
+) -> Result<(), Error> {
+    for parent in get_missing_parents(path, existing) {
+        // outside of the root nothing is created, it has to exist
+        if leaves_root(&parent) {
+            continue;
+        }
+
+        let as_file = parent.trim_end_matches('/');
+        if existing.contains(as_file) {
+            return Err(Error::DestinationExists {
+                path: as_file.to_string(),
+            });
+        }
+
+        full_rules.push(FullRule::Added {
+            path: parent.clone(),
+        });
+        existing.insert(parent);
+    }
+
+    Ok(())
 }
 
-//
-// -----------------------------
-// Phase 4 — Create edit rules
-// -----------------------------
-//
+// Renames that take an entry out of a deleted directory, in list order
+pub fn rescued_entries(rules: &[FullRule]) -> Vec<&FullRule> {
+    let deletes: Vec<&str> = rules
+        .iter()
+        .filter_map(|r| match r {
+            FullRule::Deleted { path } if path.ends_with('/') => {
+                Some(path.as_str())
+            }
+            _ => None,
+        })
+        .collect();
 
-pub fn create_edit_rules(rules: &[FullRule]) -> Vec<EditRule> {
-    let edit_rules: Vec<EditRule> = rules
+    let rescued: Vec<(&str, &FullRule)> = rules
         .iter()
-        .filter_map(|r| r.into())
+        .filter_map(|r| match r {
+            FullRule::Renamed { from, .. }
+                if deletes
+                    .iter()
+                    .any(|d| path_starts_with(from, d)) =>
+            {
+                Some((from.as_str(), r))
+            }
+            _ => None,
+        })
         .collect();
 
-    edit_rules
+    // children of a rescued directory come along with it
+    rescued
+        .iter()
+        .filter(|(from, _)| {
+            !rescued.iter().any(|(other, _)| {
+                other != from
+                    && other.ends_with('/')
+                    && path_starts_with(from, other)
+            })
+        })
+        .map(|(_, rule)| *rule)
+        .collect()
+}
+
+pub fn filter_full_rules(rules: &[FullRule]) -> Vec<FullRule> {
+    rules
+        .iter()
+        .filter(|r| !matches!(r, FullRule::Unchanged { .. }))
+        .cloned()
+        .collect()
 }
 
 fn get_missing_parents(path: &str, existing: &BTreeSet<String>) -> Vec<String> {
@@ -324,6 +531,14 @@ pub fn apply_rules_to_list(rules: &[Rule]) -> Vec<String> {
             Rule::Deleted { .. } => {
                 result.push("- ".to_string());
             }
+
+            Rule::Created { path } => {
+                result.push(format!("+ {path}"));
+            }
+
+            Rule::Copied { to, .. } => {
+                result.push(format!("c {to}"));
+            }
         }
     }
 
@@ -335,15 +550,49 @@ pub fn apply_rules_to_list(rules: &[Rule]) -> Vec<String> {
 // Rule Application Logic
 // -----------------------------
 //
-fn apply_deletes(rule: &Rule, deletes: &[String]) -> Rule {
+// Whether the closest deleted or rescued ancestor of `path` is deleted.
+// Only directories cascade, to what is strictly below them, matched
+// segment by segment. A rescue also covers the renamed entry itself, which
+// may be a file.
+fn is_deleted(path: &str, deletes: &[String], rescues: &[String]) -> bool {
+    let segments = |p: &str| {
+        p.trim_end_matches('/')
+            .split('/')
+            .count()
+    };
+
+    let below = |p: &str| {
+        path.trim_end_matches('/') != p.trim_end_matches('/')
+            && path_starts_with(path, p)
+    };
+
+    let closest_delete = deletes
+        .iter()
+        .filter(|p| p.ends_with('/') && below(p))
+        .map(|p| segments(p))
+        .max();
+    let closest_rescue = rescues
+        .iter()
+        .filter(|p| {
+            *p == path || (p.ends_with('/') && path_starts_with(path, p))
+        })
+        .map(|p| segments(p))
+        .max();
+
+    closest_delete > closest_rescue
+}
+
+fn apply_deletes(rule: &Rule, deletes: &[String], rescues: &[String]) -> Rule {
     match rule {
         Rule::Deleted { path } => Rule::Deleted { path: path.clone() },
 
+        Rule::Created { path } => Rule::Created { path: path.clone() },
+
+        // the copy is taken before anything is deleted
+        Rule::Copied { .. } => rule.clone(),
+
         Rule::Unchanged { path } => {
-            if deletes
-                .iter()
-                .any(|d| path.starts_with(d))
-            {
+            if is_deleted(path, deletes, rescues) {
                 Rule::Deleted { path: path.clone() }
             } else {
                 Rule::Unchanged { path: path.clone() }
@@ -351,10 +600,7 @@ fn apply_deletes(rule: &Rule, deletes: &[String]) -> Rule {
         }
 
         Rule::Renamed { from, to } => {
-            if deletes
-                .iter()
-                .any(|d| from.starts_with(d))
-            {
+            if is_deleted(from, deletes, rescues) {
                 Rule::Deleted { path: from.clone() }
             } else {
                 Rule::Renamed {
@@ -370,6 +616,9 @@ fn rewrite_rule(rule: &Rule, from: &str, to: &str) -> Rule {
     match rule {
         Rule::Deleted { path } => Rule::Deleted { path: path.clone() },
 
+        // already final paths
+        Rule::Created { .. } | Rule::Copied { .. } => rule.clone(),
+
         Rule::Unchanged { path } => {
             let new_path = rewrite_path(path, from, to);
 
@@ -428,6 +677,8 @@ fn rewrite_path(path: &str, from: &str, to: &str) -> String {
     result
 }
 
+// Whether `path` is `base` or below it. A `base` ending in '/' is a
+// directory, a file of the same name is not within it.
 fn path_starts_with(path: &str, base: &str) -> bool {
     let path_parts: Vec<&str> = path
         .trim_end_matches('/')
@@ -443,6 +694,13 @@ fn path_starts_with(path: &str, base: &str) -> bool {
         return false;
     }
 
+    if base_parts.len() == path_parts.len()
+        && base.ends_with('/')
+        && !path.ends_with('/')
+    {
+        return false;
+    }
+
     path_parts[..base_parts.len()] == base_parts[..]
 }
 
@@ -455,6 +713,8 @@ fn rule_depth(rule: &Rule) -> usize {
         Rule::Unchanged { path } => depth(path),
         Rule::Renamed { from, .. } => depth(from),
         Rule::Deleted { path } => depth(path),
+        Rule::Created { path } => depth(path),
+        Rule::Copied { to, .. } => depth(to),
     }
 }
 
@@ -463,5 +723,213 @@ fn rule_dest_path(rule: &Rule) -> Option<String> {
         Rule::Deleted { .. } => None,
         Rule::Unchanged { path } => Some(path.clone()),
         Rule::Renamed { to, .. } => Some(to.clone()),
+        Rule::Created { path } => Some(path.clone()),
+        Rule::Copied { to, .. } => Some(to.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    fn normalized(original: &[&str], edited: &[&str]) -> Vec<Rule> {
+        let built = build_line_rules(&lines(original), &lines(edited)).unwrap();
+        normalize_rules(&built.rules)
+    }
+
+    fn renamed(from: &str, to: &str) -> Rule {
+        Rule::Renamed {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn deleted(path: &str) -> Rule {
+        Rule::Deleted {
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_copy_and_create_lines() {
+        assert_eq!(created_path("+ new/file"), Some("new/file"));
+        assert_eq!(created_path("+new"), None);
+        assert_eq!(copied_path("c copy/"), Some("copy/"));
+        assert_eq!(copied_path("cat"), None);
+        assert_eq!(copied_path("+ c x"), None);
+    }
+
+    #[test]
+    fn pairs_lines_by_position() {
+        let built = build_line_rules(
+            &lines(&["a", "b", "c"]),
+            &lines(&["a", "+ new", "b2", "- c"]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            built.rules,
+            vec![
+                Rule::Unchanged {
+                    path: "a".to_string(),
+                },
+                renamed("b", "b2"),
+                deleted("c"),
+                Rule::Created {
+                    path: "new".to_string(),
+                },
+            ]
+        );
+        assert_eq!(built.lines, vec![Some(0), Some(2), Some(3), Some(1)]);
+    }
+
+    #[test]
+    fn refuses_lines_that_cannot_be_paired() {
+        assert!(matches!(
+            build_line_rules(&lines(&["a", "b"]), &lines(&["a"])),
+            Err(Error::LengthMismatch {
+                original: 2,
+                new: 1
+            })
+        ));
+        assert!(matches!(
+            build_line_rules(&lines(&["a"]), &lines(&["c a2", "a"])),
+            Err(Error::NothingToCopy { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn renamed_directories_carry_their_entries() {
+        let rules = normalized(
+            &["a/", "a/x", "a/y/", "a/y/z", "b"],
+            &["c/", "a/x2", "a/y/", "a/y/z", "b"],
+        );
+
+        assert_eq!(
+            rules,
+            vec![
+                renamed("a/", "c/"),
+                renamed("a/x", "c/x2"),
+                renamed("a/y/", "c/y/"),
+                renamed("a/y/z", "c/y/z"),
+                Rule::Unchanged {
+                    path: "b".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn deleted_directories_take_their_entries_along() {
+        let rules = normalized(
+            &["d/", "d/a", "d/b/", "d/b/c", "dd", "e"],
+            &["- d/", "d/a", "d/b/", "d/b/c", "dd", "- e"],
+        );
+
+        assert_eq!(
+            rules,
+            vec![
+                deleted("d/"),
+                deleted("d/a"),
+                deleted("d/b/"),
+                deleted("d/b/c"),
+                Rule::Unchanged {
+                    path: "dd".to_string(),
+                },
+                deleted("e"),
+            ]
+        );
+        assert_eq!(
+            affected_entries(&rules, "d/"),
+            vec![&deleted("d/a"), &deleted("d/b/"), &deleted("d/b/c")]
+        );
+    }
+
+    #[test]
+    fn the_closest_deleted_or_rescued_ancestor_decides() {
+        let deletes = lines(&["a/", "a/b/c/", "f"]);
+        let rescues = lines(&["a/b/", "a/x"]);
+
+        assert!(is_deleted("a/y", &deletes, &rescues));
+        assert!(!is_deleted("a/x", &deletes, &rescues));
+        assert!(!is_deleted("a/b/y", &deletes, &rescues));
+        assert!(is_deleted("a/b/c/y", &deletes, &rescues));
+        // only directories cascade, by whole segments
+        assert!(!is_deleted("f/g", &deletes, &rescues));
+        assert!(!is_deleted("ab/y", &deletes, &rescues));
+    }
+
+    #[test]
+    fn original_entries_named_like_copies_stay_paired() {
+        let original = lines(&["+ plus", "a", "c notes.txt"]);
+
+        let built = build_line_rules(&original, &original).unwrap();
+        assert!(
+            built
+                .rules
+                .iter()
+                .all(|rule| matches!(rule, Rule::Unchanged { .. }))
+        );
+
+        let edited = lines(&["+ plus", "a", "c a2", "c notes.txt", "+ new"]);
+        let built = build_line_rules(&original, &edited).unwrap();
+        assert_eq!(
+            built.rules[3..],
+            [
+                Rule::Copied {
+                    from: "a".to_string(),
+                    to: "a2".to_string(),
+                },
+                Rule::Created {
+                    path: "new".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            built.lines,
+            vec![Some(0), Some(1), Some(3), Some(2), Some(4)]
+        );
+    }
+
+    #[test]
+    fn rescues_files_and_directories_out_of_deleted_directories() {
+        let rules = normalized(
+            &["old/", "old/gone", "old/keep.txt", "old/sub/", "old/sub/a"],
+            &["- old/", "old/gone", "keep.txt", "sub/", "old/sub/a"],
+        );
+
+        assert_eq!(
+            rules,
+            vec![
+                deleted("old/"),
+                deleted("old/gone"),
+                renamed("old/keep.txt", "keep.txt"),
+                renamed("old/sub/", "sub/"),
+                renamed("old/sub/a", "sub/a"),
+            ]
+        );
+
+        let full_rules = add_missing_directories(&rules).unwrap();
+        let rescued: Vec<&str> = rescued_entries(&full_rules)
+            .into_iter()
+            .filter_map(|rule| match rule {
+                FullRule::Renamed { from, .. } => Some(from.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rescued, vec!["old/keep.txt", "old/sub/"]);
+
+        // a file taking the name of the deleted directory is not inside it
+        assert_eq!(
+            normalized(&["a/", "a/a"], &["- a/", "a"]),
+            vec![deleted("a/"), renamed("a/a", "a")]
+        );
     }
 }
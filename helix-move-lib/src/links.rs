@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{FullRule, Operation};
+
+//
+// -----------------------------
+// Phase 6 — Relink
+// -----------------------------
+//
+
+// Rewrites relative link targets so they still resolve once the links or
+// their targets moved. `links` maps listed symlinks to their targets.
+pub fn plan_relinks(
+    rules: &[FullRule],
+    links: &BTreeMap<String, String>,
+) -> Vec<Operation> {
+    // original path -> final path, None if deleted
+    let mut finals: HashMap<&str, Option<&str>> = HashMap::new();
+
+    for rule in rules {
+        let (from, to) = match rule {
+            FullRule::Unchanged { path } => (path, Some(path)),
+            FullRule::Renamed { from, to } => (from, Some(to)),
+            FullRule::Deleted { path } => (path, None),
+            FullRule::Added { .. } | FullRule::Copied { .. } => continue,
+        };
+
+        finals.insert(
+            from.trim_end_matches('/'),
+            to.map(|to| to.trim_end_matches('/')),
+        );
+    }
+
+    let mut operations = Vec::new();
+
+    for (link, target) in links {
+        if target.starts_with('/') {
+            continue;
+        }
+
+        let Some(Some(new_link)) = finals.get(link.as_str()) else {
+            continue;
+        };
+
+        let resolved = resolve(parent(link), target);
+
+        // outside of the root only the link itself can move
+        let new_resolved = if resolved.first() == Some(&"..") {
+            resolved.clone()
+        } else {
+            let Some(new_resolved) = follow(&resolved, &finals) else {
+                continue;
+            };
+            new_resolved
+        };
+
+        // untouched, don't normalize targets like "./a"
+        if *new_link == link.trim_end_matches('/') && new_resolved == resolved {
+            continue;
+        }
+
+        let mut new_target = relative(parent(new_link), &new_resolved);
+        if target.ends_with('/') && !new_target.ends_with('/') {
+            new_target.push('/');
+        }
+
+        if new_target != *target {
+            operations.push(Operation::Relink {
+                path: new_link.to_string(),
+                from: target.clone(),
+                to: new_target,
+            });
+        }
+    }
+
+    operations
+}
+
+fn parent(path: &str) -> Vec<&str> {
+    let mut parts = split(path);
+    parts.pop();
+    parts
+}
+
+fn split(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+// Lexically resolves `target` from `dir`, leading ".." if it leaves the
+// root
+fn resolve<'a>(dir: Vec<&'a str>, target: &'a str) -> Vec<&'a str> {
+    let mut parts = dir;
+
+    for part in split(target) {
+        match part {
+            "." => {}
+            ".." if parts.last().is_some_and(|&p| p != "..") => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    parts
+}
+
+// Final path of `path`, following the closest listed ancestor. None if
+// that ancestor is deleted.
+fn follow<'a>(
+    path: &[&'a str],
+    finals: &HashMap<&str, Option<&'a str>>,
+) -> Option<Vec<&'a str>> {
+    for len in (1..=path.len()).rev() {
+        let prefix = path[..len].join("/");
+
+        if let Some(new_prefix) = finals.get(prefix.as_str()) {
+            let mut new_path = split((*new_prefix)?);
+            new_path.extend_from_slice(&path[len..]);
+            return Some(new_path);
+        }
+    }
+
+    Some(path.to_vec())
+}
+
+// Path from `dir` to `target`, both relative to the root
+fn relative(dir: Vec<&str>, target: &[&str]) -> String {
+    let common = dir
+        .iter()
+        .zip(target)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<&str> = vec![".."; dir.len() - common];
+    parts.extend_from_slice(&target[common..]);
+
+    if parts.is_empty() {
+        ".".to_string()
+    } else {
+        parts.join("/")
+    }
+}
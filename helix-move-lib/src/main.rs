@@ -6,7 +6,7 @@ fn s(v: &[&str]) -> Vec<String> {
         .collect()
 }
 
-fn main() {
+fn main() -> Result<(), Error> {
     let original = s(&[
         "./testdel/abc/d",
         "./testdel/abc/d/test3",
@@ -17,7 +17,7 @@ fn main() {
         "./testdel/abxc/d/test3",
     ]);
 
-    let rules = build_rules(&original, &new);
+    let rules = build_rules(&original, &new)?;
     let normalized_rules = normalize_rules(&rules);
     println!("{:#?}", normalized_rules);
 
@@ -39,9 +39,11 @@ fn main() {
         "./testdel/abc/d/test3",
     ]);
 
-    let rules = build_rules(&original, &new);
+    let rules = build_rules(&original, &new)?;
     let normalized_rules = normalize_rules(&rules);
     println!("{:#?}", normalized_rules);
 
     assert_eq!(normalized_rules, normalize_rules(&normalized_rules));
+
+    Ok(())
 }
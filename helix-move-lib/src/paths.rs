@@ -0,0 +1,157 @@
+use std::path::{Component, Path};
+
+use crate::{Rule, path_starts_with};
+
+//
+// -----------------------------
+// Phase 1b — Canonicalize destinations
+// -----------------------------
+//
+// Edited lines are taken lexically, without looking at the disk: "." and
+// ".." are resolved and repeated slashes collapsed. Destinations that leave
+// the root keep their leading "..", absolute ones are made relative to the
+// root.
+//
+
+pub fn canonicalize_rules(root: &Path, rules: &[Rule]) -> Vec<Rule> {
+    rules
+        .iter()
+        .map(|rule| match rule {
+            Rule::Renamed { from, to } => {
+                let to = canonicalize_path(root, to);
+
+                if &to == from {
+                    Rule::Unchanged { path: to }
+                } else {
+                    Rule::Renamed {
+                        from: from.clone(),
+                        to,
+                    }
+                }
+            }
+            Rule::Created { path } => Rule::Created {
+                path: canonicalize_path(root, path),
+            },
+            Rule::Copied { from, to } => Rule::Copied {
+                from: from.clone(),
+                to: canonicalize_path(root, to),
+            },
+            rule => rule.clone(),
+        })
+        .collect()
+}
+
+// `path` relative to `root` (which must be absolute and canonical)
+pub fn canonicalize_path(root: &Path, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+
+    if path.starts_with('/') {
+        let base: Vec<&str> = root
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect();
+
+        // resolved from "/", where ".." has nowhere to go
+        let mut absolute: Vec<&str> = Vec::new();
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    absolute.pop();
+                }
+                part => absolute.push(part),
+            }
+        }
+
+        let common = base
+            .iter()
+            .zip(&absolute)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        parts.extend(std::iter::repeat_n("..", base.len() - common));
+        parts.extend_from_slice(&absolute[common..]);
+    } else {
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." if parts.last().is_some_and(|&p| p != "..") => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+    }
+
+    let mut canonical = parts.join("/");
+    if path.ends_with('/') && !canonical.is_empty() {
+        canonical.push('/');
+    }
+
+    canonical
+}
+
+// Whether a canonical path points outside of the root
+pub fn leaves_root(path: &str) -> bool {
+    path.starts_with('/') || path == ".." || path.starts_with("../")
+}
+
+// Root of a session over several roots (see `validate_roots`) that `path`
+// belongs to
+pub fn root_of<'a>(path: &str, roots: &'a [String]) -> Option<&'a str> {
+    roots
+        .iter()
+        .find(|root| path_starts_with(path, root))
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destinations_are_resolved_lexically() {
+        let root = Path::new("/home/u/work");
+        let cases = [
+            ("a/./b", "a/b"),
+            ("./a/", "a/"),
+            ("a//b///c/", "a/b/c/"),
+            ("a/../b", "b"),
+            ("a/../../x", "../x"),
+            ("../../x/", "../../x/"),
+            ("/home/u/work/a/b", "a/b"),
+            ("/home/u/work/../work//a", "a"),
+            ("/home/u/other/f", "../other/f"),
+            ("/etc/passwd", "../../../etc/passwd"),
+            ("/..", "../../.."),
+        ];
+
+        for (path, canonical) in cases {
+            assert_eq!(canonicalize_path(root, path), canonical, "{path}");
+        }
+    }
+
+    #[test]
+    fn only_leading_parents_leave_the_root() {
+        let root = Path::new("/home/u/work");
+        let cases = [
+            ("../x", true),
+            ("a/../../x", true),
+            ("/home/u/work/a", false),
+            ("/home/u/workshop/a", true),
+            ("/tmp/x", true),
+            ("a/..", false),
+            ("..a", false),
+            ("a/..b/c", false),
+        ];
+
+        for (path, leaves) in cases {
+            let canonical = canonicalize_path(root, path);
+            assert_eq!(leaves_root(&canonical), leaves, "{path}");
+        }
+        assert!(leaves_root(".."));
+    }
+}
@@ -0,0 +1,968 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, FullRule, HardLinks, MetadataLoss, Progress, copy_entry, depth,
+    leaves_root, move_entry_linked, parent_dir, path_starts_with, rewrite_path,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Operation {
+    CreateDir {
+        path: String,
+    },
+    CreateFile {
+        path: String,
+        /// File whose contents and mode seed the new file
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        template: Option<PathBuf>,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Remove {
+        path: String,
+    },
+    /// Moves the entry at the absolute `trash_path` back to `to`
+    Restore {
+        trash_path: PathBuf,
+        to: String,
+    },
+    /// Recursively copies `from` to the free path `to`
+    Copy {
+        from: String,
+        to: String,
+    },
+    /// Points the symlink at `path` to `to` instead of `from`
+    Relink {
+        path: String,
+        from: String,
+        to: String,
+    },
+}
+
+impl Operation {
+    pub fn apply(&self, root: &Path) -> Result<Vec<MetadataLoss>, Error> {
+        self.apply_with_progress(root, &mut HardLinks::default(), &mut |_| {})
+    }
+
+    // Like `apply`, reporting on renames that have to copy across
+    // filesystems. Such copies stay hard linked to the earlier ones in
+    // `links`, which should be shared by all operations of a session.
+    pub fn apply_with_progress(
+        &self,
+        root: &Path,
+        links: &mut HardLinks,
+        progress: &mut dyn FnMut(Progress),
+    ) -> Result<Vec<MetadataLoss>, Error> {
+        let root = root
+            .canonicalize()
+            .map_err(Error::io(root))?;
+
+        let mut losses = Vec::new();
+
+        match self {
+            Operation::CreateDir { path } => {
+                let full_path = root.join(path);
+
+                if fs::symlink_metadata(&full_path).is_ok() {
+                    return Err(Error::DestinationExists {
+                        path: path.clone(),
+                    });
+                }
+
+                fs::create_dir(&full_path).map_err(Error::io(full_path))?;
+            }
+            Operation::CreateFile { path, template } => {
+                let full_path = root.join(path);
+
+                let mut file =
+                    fs::File::create_new(&full_path).map_err(|e| {
+                        match e.kind() {
+                            io::ErrorKind::AlreadyExists => {
+                                Error::DestinationExists { path: path.clone() }
+                            }
+                            _ => Error::io(&full_path)(e),
+                        }
+                    })?;
+
+                if let Some(template) = template {
+                    let mut source = fs::File::open(template)
+                        .map_err(Error::io(template))?;
+                    io::copy(&mut source, &mut file)
+                        .map_err(Error::io(&full_path))?;
+
+                    let permissions = source
+                        .metadata()
+                        .map_err(Error::io(template))?
+                        .permissions();
+                    fs::set_permissions(&full_path, permissions)
+                        .map_err(Error::io(full_path))?;
+                }
+            }
+            Operation::Rename {
+                from: raw_from,
+                to: raw_to,
+            } => {
+                let from = root.join(raw_from.trim_end_matches('/'));
+                let to = root.join(raw_to.trim_end_matches('/'));
+
+                if fs::symlink_metadata(&from).is_err() {
+                    return Err(Error::MissingSource {
+                        path: raw_from.clone(),
+                    });
+                }
+
+                // fs::rename silently replaces files, never allow that
+                if fs::symlink_metadata(&to).is_ok() {
+                    return Err(Error::DestinationExists {
+                        path: raw_to.clone(),
+                    });
+                }
+
+                losses = move_entry_linked(&from, &to, links, progress)?;
+            }
+            Operation::Restore {
+                trash_path,
+                to: raw_to,
+            } => {
+                let to = root.join(raw_to.trim_end_matches('/'));
+
+                if fs::symlink_metadata(trash_path).is_err() {
+                    return Err(Error::MissingSource {
+                        path: trash_path.display().to_string(),
+                    });
+                }
+
+                if fs::symlink_metadata(&to).is_ok() {
+                    return Err(Error::DestinationExists {
+                        path: raw_to.clone(),
+                    });
+                }
+
+                losses = move_entry_linked(trash_path, &to, links, progress)?;
+            }
+            Operation::Remove { path } => {
+                let full_path = root.join(path.trim_end_matches('/'));
+
+                if fs::symlink_metadata(&full_path).is_err() {
+                    return Err(Error::MissingSource { path: path.clone() });
+                }
+
+                if path.ends_with('/') {
+                    fs::remove_dir_all(&full_path)
+                        .map_err(Error::io(full_path))?;
+                } else {
+                    fs::remove_file(&full_path)
+                        .map_err(Error::io(full_path))?;
+                }
+            }
+            Operation::Copy {
+                from: raw_from,
+                to: raw_to,
+            } => {
+                let from = root.join(raw_from.trim_end_matches('/'));
+                let to = root.join(raw_to.trim_end_matches('/'));
+
+                if fs::symlink_metadata(&from).is_err() {
+                    return Err(Error::MissingSource {
+                        path: raw_from.clone(),
+                    });
+                }
+
+                if fs::symlink_metadata(&to).is_ok() {
+                    return Err(Error::DestinationExists {
+                        path: raw_to.clone(),
+                    });
+                }
+
+                losses = copy_entry(&from, &to)?;
+            }
+            Operation::Relink { path, from, to } => {
+                let full_path = root.join(path);
+
+                let target = fs::read_link(&full_path).map_err(|_| {
+                    Error::LinkChanged {
+                        path: path.clone(),
+                        target: from.clone(),
+                    }
+                })?;
+
+                if target != Path::new(from) {
+                    return Err(Error::LinkChanged {
+                        path: path.clone(),
+                        target: from.clone(),
+                    });
+                }
+
+                // replace through a rename, the link never goes missing
+                let temp_path = full_path.with_file_name(format!(
+                    ".{}.helix-move-relink",
+                    full_path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                ));
+
+                std::os::unix::fs::symlink(to, &temp_path)
+                    .map_err(Error::io(&temp_path))?;
+                fs::rename(&temp_path, &full_path)
+                    .map_err(Error::io(full_path))?;
+            }
+        }
+
+        Ok(losses)
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::CreateDir { path }
+            | Operation::CreateFile {
+                path,
+                template: None,
+            } => write!(f, "create {path}"),
+            Operation::CreateFile {
+                path,
+                template: Some(template),
+            } => write!(f, "create {path} from {}", template.display()),
+            Operation::Rename { from, to } => {
+                write!(f, "rename {from} -> {to}")
+            }
+            Operation::Remove { path } => write!(f, "remove {path}"),
+            Operation::Restore { trash_path, to } => {
+                write!(f, "restore {} -> {to}", trash_path.display())
+            }
+            Operation::Copy { from, to } => write!(f, "copy {from} -> {to}"),
+            Operation::Relink { path, from, to } => {
+                write!(f, "relink {path}: {from} -> {to}")
+            }
+        }
+    }
+}
+
+//
+// -----------------------------
+// Phase 5 — Plan operations
+// -----------------------------
+//
+
+// Orders renames so that every destination is free when it is reached:
+// chains run back to front, cycles are broken through temporary names.
+pub fn plan_operations(rules: &[FullRule]) -> Result<Vec<Operation>, Error> {
+    let mut tree = Tree::default();
+
+    // ---- STEP 1: BUILD ORIGINAL TREE ----
+
+    let mut targets: Vec<(usize, String)> = Vec::new();
+    let mut deleted: Vec<usize> = Vec::new();
+    let mut added: Vec<String> = Vec::new();
+    let mut copies: Vec<(usize, String)> = Vec::new();
+
+    for rule in rules {
+        match rule {
+            FullRule::Unchanged { path } => {
+                targets.push((tree.node_for(path), path.clone()));
+            }
+            FullRule::Renamed { from, to } => {
+                targets.push((tree.node_for(from), to.clone()));
+            }
+            FullRule::Deleted { path } => {
+                deleted.push(tree.node_for(path));
+            }
+            FullRule::Added { path } => {
+                added.push(path.clone());
+            }
+            FullRule::Copied { from, to } => {
+                copies.push((tree.node_for(from), to.clone()));
+            }
+        }
+    }
+
+    let deleted_set: HashSet<usize> = deleted.iter().copied().collect();
+
+    // every node (listed or implicit ancestor) by its final path
+    let mut finals: HashMap<String, usize> = targets
+        .iter()
+        .map(|(id, path)| (path.clone(), *id))
+        .collect();
+
+    let listed: HashSet<usize> = targets
+        .iter()
+        .map(|(id, _)| *id)
+        .collect();
+
+    for (path, &id) in &tree.by_path {
+        if !listed.contains(&id) && !deleted_set.contains(&id) {
+            finals.entry(path.clone()).or_insert(id);
+        }
+    }
+
+    let mut creates: HashSet<usize> = HashSet::new();
+    for path in added {
+        if finals.contains_key(&path) {
+            continue;
+        }
+
+        let id = tree.detached(&path);
+        finals.insert(path.clone(), id);
+        targets.push((id, path));
+        creates.insert(id);
+    }
+
+    let mut operations = Vec::new();
+
+    // ---- STEP 2: COPY ----
+
+    // Copies are taken first, from the original entries, into temporary
+    // names next to them. From there they move like any other entry.
+    for (source, to) in copies {
+        let parent = tree.outside_deleted(source, &deleted_set);
+        let temp = tree.temp_name(parent, &tree.nodes[source].name);
+        let dir = to.ends_with('/');
+
+        let id = tree.detached(&to);
+        tree.nodes[id].dir = dir;
+        tree.attach(id, parent, &temp);
+
+        operations.push(Operation::Copy {
+            from: tree.path(source),
+            to: tree.path(id),
+        });
+
+        finals.insert(to.clone(), id);
+        targets.push((id, to));
+    }
+
+    // ---- STEP 3: REMOVE DELETED SUBTREES ----
+
+    // entries that stay are moved out of deleted directories first, into
+    // temporary names next to the outermost one
+    let mut candidates: Vec<usize> = targets
+        .iter()
+        .map(|(id, _)| *id)
+        .filter(|id| !creates.contains(id) && !deleted_set.contains(id))
+        .collect();
+    candidates.sort_by_key(|&id| depth(&tree.path(id)));
+
+    let mut rescued: HashSet<usize> = HashSet::new();
+    for id in candidates {
+        // the closest deleted or rescued ancestor decides
+        let mut inside = false;
+        let mut ancestor = tree.nodes[id].parent;
+        while let Some(parent) = ancestor {
+            if rescued.contains(&parent) || deleted_set.contains(&parent) {
+                inside = deleted_set.contains(&parent);
+                break;
+            }
+            ancestor = tree.nodes[parent].parent;
+        }
+
+        if !inside {
+            continue;
+        }
+
+        let parent = tree.outside_deleted(id, &deleted_set);
+        let temp = tree.temp_name(parent, &tree.nodes[id].name);
+        let from = tree.path(id);
+
+        tree.detach(id);
+        tree.attach(id, parent, &temp);
+
+        operations.push(Operation::Rename {
+            from,
+            to: tree.path(id),
+        });
+        rescued.insert(id);
+    }
+
+    for &id in &deleted {
+        let parent_deleted = tree.nodes[id]
+            .parent
+            .is_some_and(|p| deleted_set.contains(&p));
+
+        if !parent_deleted {
+            operations.push(Operation::Remove {
+                path: tree.path(id),
+            });
+        }
+    }
+
+    for &id in &deleted {
+        tree.detach(id);
+    }
+
+    // ---- STEP 4: CREATE AND MOVE ----
+
+    let mut pending: Vec<Step> = Vec::new();
+
+    for (id, path) in &targets {
+        let (parent, name) = split_path(path);
+        let parent = match parent {
+            Some(parent) => match finals.get(&parent) {
+                Some(&id) => Some(id),
+                // directories outside of the root are taken as they are
+                None if leaves_root(&parent) => Some(tree.node_for(&parent)),
+                None => {
+                    return Err(Error::MissingParent { path: path.clone() });
+                }
+            },
+            None => None,
+        };
+
+        let node = &tree.nodes[*id];
+        let create = creates.contains(id);
+
+        if !create && node.parent == parent && node.name == name {
+            continue;
+        }
+
+        pending.push(Step {
+            depth: depth(path.trim_end_matches('/')),
+            id: *id,
+            parent,
+            name,
+            create,
+        });
+    }
+
+    // shallow first keeps the plan readable, dependencies decide the rest
+    pending.sort_by_key(|step| step.depth);
+
+    while !pending.is_empty() {
+        if let Some(i) = pending
+            .iter()
+            .position(|step| tree.is_ready(step))
+        {
+            let step = pending.remove(i);
+            let to = tree.child_path(step.parent, &step.name, step.dir(&tree));
+
+            if step.create && step.dir(&tree) {
+                operations.push(Operation::CreateDir { path: to });
+            } else if step.create {
+                operations.push(Operation::CreateFile {
+                    path: to,
+                    template: None,
+                });
+            } else {
+                operations.push(Operation::Rename {
+                    from: tree.path(step.id),
+                    to,
+                });
+                tree.detach(step.id);
+            }
+
+            tree.attach(step.id, step.parent, &step.name);
+            continue;
+        }
+
+        // Nothing can run: some destination is held by an entry that is
+        // itself waiting (a swap or rotation). Park that entry under a
+        // temporary name so the cycle can unwind.
+        let blocker = pending.iter().find_map(|step| {
+            tree.lookup(step.parent, &step.name)
+                .filter(|occupant| {
+                    pending
+                        .iter()
+                        .any(|other| other.id == *occupant && !other.create)
+                })
+        });
+
+        let Some(blocker) = blocker else {
+            let step = &pending[0];
+            let path =
+                tree.child_path(step.parent, &step.name, step.dir(&tree));
+
+            if tree
+                .lookup(step.parent, &step.name)
+                .is_some()
+            {
+                return Err(Error::DestinationExists { path });
+            }
+
+            return Err(Error::MissingParent { path });
+        };
+
+        let parent = tree.nodes[blocker].parent;
+        let temp = tree.temp_name(parent, &tree.nodes[blocker].name);
+        let from = tree.path(blocker);
+
+        tree.detach(blocker);
+        tree.attach(blocker, parent, &temp);
+
+        operations.push(Operation::Rename {
+            from,
+            to: tree.path(blocker),
+        });
+    }
+
+    Ok(operations)
+}
+
+// Replays operations on a path listing, as if they ran on disk
+pub fn simulate_operations(
+    entries: &[String],
+    operations: &[Operation],
+) -> Result<Vec<String>, Error> {
+    let mut entries: Vec<String> = entries.to_vec();
+
+    let exists = |entries: &[String], path: &str| {
+        entries
+            .iter()
+            .any(|e| e.trim_end_matches('/') == path.trim_end_matches('/'))
+    };
+
+    for operation in operations {
+        match operation {
+            Operation::CreateDir { path }
+            | Operation::CreateFile { path, .. }
+            | Operation::Restore { to: path, .. } => {
+                if exists(&entries, path) {
+                    return Err(Error::DestinationExists {
+                        path: path.clone(),
+                    });
+                }
+                entries.push(path.clone());
+            }
+            Operation::Rename { from, to } => {
+                if !exists(&entries, from) {
+                    return Err(Error::MissingSource { path: from.clone() });
+                }
+                if exists(&entries, to) {
+                    return Err(Error::DestinationExists { path: to.clone() });
+                }
+
+                for entry in entries.iter_mut() {
+                    if entry.trim_end_matches('/') == from.trim_end_matches('/')
+                    {
+                        *entry = to.clone();
+                    } else if from.ends_with('/')
+                        && path_starts_with(entry, from)
+                    {
+                        *entry = rewrite_path(entry, from, to);
+                    }
+                }
+            }
+            Operation::Remove { path } => {
+                if !exists(&entries, path) {
+                    return Err(Error::MissingSource { path: path.clone() });
+                }
+
+                entries.retain(|entry| {
+                    !(entry == path
+                        || path.ends_with('/') && path_starts_with(entry, path))
+                });
+            }
+            Operation::Copy { from, to } => {
+                if !exists(&entries, from) {
+                    return Err(Error::MissingSource { path: from.clone() });
+                }
+                if exists(&entries, to) {
+                    return Err(Error::DestinationExists { path: to.clone() });
+                }
+
+                let copied: Vec<String> = entries
+                    .iter()
+                    .filter(|entry| {
+                        entry.trim_end_matches('/')
+                            == from.trim_end_matches('/')
+                            || from.ends_with('/')
+                                && path_starts_with(entry, from)
+                    })
+                    .map(|entry| match entry == from {
+                        true => to.clone(),
+                        false => rewrite_path(entry, from, to),
+                    })
+                    .collect();
+                entries.extend(copied);
+            }
+            Operation::Relink { path, .. } => {
+                if !exists(&entries, path) {
+                    return Err(Error::MissingSource { path: path.clone() });
+                }
+            }
+        }
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+struct Step {
+    depth: usize,
+    id: usize,
+    parent: Option<usize>,
+    name: String,
+    create: bool,
+}
+
+impl Step {
+    fn dir(&self, tree: &Tree) -> bool {
+        tree.nodes[self.id].dir
+    }
+}
+
+fn split_path(path: &str) -> (Option<String>, String) {
+    let trimmed = path.trim_end_matches('/');
+
+    match parent_dir(path) {
+        Some(parent) => {
+            let name = trimmed[parent.len()..].to_string();
+            (Some(parent), name)
+        }
+        None => (None, trimmed.to_string()),
+    }
+}
+
+//
+// -----------------------------
+// Simulated directory tree
+// -----------------------------
+//
+
+struct Node {
+    parent: Option<usize>,
+    name: String,
+    dir: bool,
+    attached: bool,
+}
+
+#[derive(Default)]
+struct Tree {
+    nodes: Vec<Node>,
+    children: HashMap<(Option<usize>, String), usize>,
+    by_path: HashMap<String, usize>,
+}
+
+impl Tree {
+    // Node for an original path, creating implicit ancestors on the way
+    fn node_for(&mut self, path: &str) -> usize {
+        if let Some(&id) = self.by_path.get(path) {
+            return id;
+        }
+
+        let (parent, name) = split_path(path);
+        let parent = parent.map(|p| self.node_for(&p));
+
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            parent,
+            name: name.clone(),
+            dir: path.ends_with('/'),
+            attached: true,
+        });
+        self.children.insert((parent, name), id);
+        self.by_path
+            .insert(path.to_string(), id);
+
+        id
+    }
+
+    // Node that does not exist on disk yet
+    fn detached(&mut self, path: &str) -> usize {
+        let (_, name) = split_path(path);
+
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            parent: None,
+            name,
+            dir: path.ends_with('/'),
+            attached: false,
+        });
+
+        id
+    }
+
+    fn lookup(&self, parent: Option<usize>, name: &str) -> Option<usize> {
+        self.children
+            .get(&(parent, name.to_string()))
+            .copied()
+    }
+
+    fn detach(&mut self, id: usize) {
+        let node = &mut self.nodes[id];
+        node.attached = false;
+        let key = (node.parent, node.name.clone());
+
+        if self.children.get(&key) == Some(&id) {
+            self.children.remove(&key);
+        }
+    }
+
+    fn attach(&mut self, id: usize, parent: Option<usize>, name: &str) {
+        let node = &mut self.nodes[id];
+        node.parent = parent;
+        node.name = name.to_string();
+        node.attached = true;
+
+        self.children
+            .insert((parent, name.to_string()), id);
+    }
+
+    fn is_ready(&self, step: &Step) -> bool {
+        let parent_exists = step
+            .parent
+            .is_none_or(|parent| self.nodes[parent].attached);
+
+        let free = self
+            .lookup(step.parent, &step.name)
+            .is_none();
+
+        parent_exists && free && !self.is_within(step.parent, step.id)
+    }
+
+    // Whether `node` currently lies inside (or is) `ancestor`
+    fn is_within(&self, node: Option<usize>, ancestor: usize) -> bool {
+        let mut current = node;
+
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = self.nodes[id].parent;
+        }
+
+        false
+    }
+
+    // Closest ancestor of `id` that is not inside a deleted directory
+    fn outside_deleted(
+        &self,
+        id: usize,
+        deleted: &HashSet<usize>,
+    ) -> Option<usize> {
+        let mut outside = self.nodes[id].parent;
+        let mut ancestor = outside;
+
+        while let Some(current) = ancestor {
+            ancestor = self.nodes[current].parent;
+            if deleted.contains(&current) {
+                outside = ancestor;
+            }
+        }
+
+        outside
+    }
+
+    fn temp_name(&self, parent: Option<usize>, name: &str) -> String {
+        (0..)
+            .map(|n| format!(".{name}.helix-move-{n}"))
+            .find(|temp| self.lookup(parent, temp).is_none())
+            .unwrap()
+    }
+
+    fn path(&self, id: usize) -> String {
+        let node = &self.nodes[id];
+        self.child_path(node.parent, &node.name, node.dir)
+    }
+
+    fn child_path(
+        &self,
+        parent: Option<usize>,
+        name: &str,
+        dir: bool,
+    ) -> String {
+        let mut path = match parent {
+            Some(parent) => format!("{}{name}", self.path(parent)),
+            None => name.to_string(),
+        };
+
+        if dir {
+            path.push('/');
+        }
+
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renamed(from: &str, to: &str) -> FullRule {
+        FullRule::Renamed {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn unchanged(path: &str) -> FullRule {
+        FullRule::Unchanged {
+            path: path.to_string(),
+        }
+    }
+
+    fn planned(rules: &[FullRule]) -> Vec<String> {
+        plan_operations(rules)
+            .unwrap()
+            .iter()
+            .map(Operation::to_string)
+            .collect()
+    }
+
+    fn listing(entries: &[&str]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn chains_run_back_to_front() {
+        assert_eq!(
+            planned(&[renamed("a", "b"), renamed("b", "c")]),
+            ["rename b -> c", "rename a -> b"]
+        );
+    }
+
+    #[test]
+    fn swaps_go_through_a_temporary_name() {
+        assert_eq!(
+            planned(&[renamed("a", "b"), renamed("b", "a")]),
+            [
+                "rename b -> .b.helix-move-0",
+                "rename a -> b",
+                "rename .b.helix-move-0 -> a",
+            ]
+        );
+
+        assert_eq!(
+            planned(&[renamed("x/", "y/"), renamed("y/", "x/")]),
+            [
+                "rename y/ -> .y.helix-move-0/",
+                "rename x/ -> y/",
+                "rename .y.helix-move-0/ -> x/",
+            ]
+        );
+    }
+
+    #[test]
+    fn directories_and_files_swap_names() {
+        let original = listing(&["a/", "a/f", "z"]);
+        let rules =
+            crate::build_rules(&original, &listing(&["z/", "a/f", "a"]))
+                .unwrap();
+        let full_rules =
+            crate::add_missing_directories(&crate::normalize_rules(&rules))
+                .unwrap();
+        let operations = plan_operations(&full_rules).unwrap();
+
+        assert_eq!(
+            operations
+                .iter()
+                .map(Operation::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "rename z -> .z.helix-move-0",
+                "rename a/ -> z/",
+                "rename .z.helix-move-0 -> a",
+            ]
+        );
+        assert_eq!(
+            simulate_operations(&original, &operations).unwrap(),
+            listing(&["a", "z/", "z/f"])
+        );
+    }
+
+    #[test]
+    fn rotations_park_a_single_entry() {
+        let rules = [renamed("a", "b"), renamed("b", "c"), renamed("c", "a")];
+        let operations = plan_operations(&rules).unwrap();
+
+        assert_eq!(
+            operations
+                .iter()
+                .map(Operation::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "rename b -> .b.helix-move-0",
+                "rename a -> b",
+                "rename c -> a",
+                "rename .b.helix-move-0 -> c",
+            ]
+        );
+        assert_eq!(
+            simulate_operations(&listing(&["a", "b", "c"]), &operations)
+                .unwrap(),
+            listing(&["a", "b", "c"])
+        );
+    }
+
+    #[test]
+    fn temporary_names_skip_taken_ones() {
+        assert_eq!(
+            planned(&[
+                renamed("a", "b"),
+                renamed("b", "a"),
+                unchanged(".b.helix-move-0"),
+            ]),
+            [
+                "rename b -> .b.helix-move-1",
+                "rename a -> b",
+                "rename .b.helix-move-1 -> a",
+            ]
+        );
+    }
+
+    #[test]
+    fn rescues_copies_and_creates_around_a_deleted_directory() {
+        let rules = [
+            FullRule::Deleted {
+                path: "d/".to_string(),
+            },
+            FullRule::Deleted {
+                path: "d/a".to_string(),
+            },
+            renamed("d/k", "k"),
+            FullRule::Copied {
+                from: "d/k".to_string(),
+                to: "k2".to_string(),
+            },
+            FullRule::Added {
+                path: "n/".to_string(),
+            },
+            FullRule::Added {
+                path: "n/f".to_string(),
+            },
+        ];
+        let operations = plan_operations(&rules).unwrap();
+
+        assert_eq!(
+            operations
+                .iter()
+                .map(Operation::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "copy d/k -> .k.helix-move-0",
+                "rename d/k -> .k.helix-move-1",
+                "remove d/",
+                "rename .k.helix-move-1 -> k",
+                "create n/",
+                "rename .k.helix-move-0 -> k2",
+                "create n/f",
+            ]
+        );
+        assert_eq!(
+            simulate_operations(&listing(&["d/", "d/a", "d/k"]), &operations)
+                .unwrap(),
+            listing(&["k", "k2", "n/", "n/f"])
+        );
+    }
+
+    #[test]
+    fn taken_destinations_are_refused() {
+        assert!(matches!(
+            plan_operations(&[renamed("a", "b"), unchanged("b")]),
+            Err(Error::DestinationExists { path }) if path == "b"
+        ));
+    }
+}
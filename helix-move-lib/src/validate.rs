@@ -0,0 +1,524 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    fs,
+    path::Path,
+};
+
+use crate::{Rule, leaves_root, path_starts_with, root_of};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    DuplicateDestination,
+    CollidesWithUntouched,
+    KindMismatch,
+    IntoOwnDescendant,
+    EmptySegment,
+    SurroundingWhitespace,
+    OutsideRoot,
+    RootChanged,
+    NameTooLong,
+    ExistsOnDisk,
+}
+
+// Longest file name most filesystems accept, in bytes
+const NAME_MAX: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Zero-based line in the edited list
+    pub line: usize,
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(line: usize, kind: DiagnosticKind, message: String) -> Self {
+        Diagnostic {
+            line,
+            severity: Severity::Error,
+            kind,
+            message,
+        }
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}: {}: {}",
+            self.line + 1,
+            self.severity,
+            self.message
+        )
+    }
+}
+
+// Edited line of rule `i`. An entry whose line was removed (in id mode)
+// is reported next to where it was, on the line of the closest earlier
+// entry that is still listed.
+fn edited_line(lines: &[Option<usize>], i: usize) -> usize {
+    match lines.get(i) {
+        Some(Some(line)) => *line,
+        Some(None) => lines[..i]
+            .iter()
+            .rev()
+            .find_map(|line| *line)
+            .unwrap_or(0),
+        None => i,
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|d| d.severity == Severity::Error)
+}
+
+//
+// -----------------------------
+// Phase 2b — Validate
+// -----------------------------
+//
+
+// Expects normalized rules, one per line of the edited list
+pub fn validate(rules: &[Rule]) -> Vec<Diagnostic> {
+    let lines: Vec<Option<usize>> = (0..rules.len()).map(Some).collect();
+    validate_lines(rules, &lines)
+}
+
+// Like `validate`, for rules that map to edited lines through `lines` (see
+// `LineRules`)
+pub fn validate_lines(
+    rules: &[Rule],
+    lines: &[Option<usize>],
+) -> Vec<Diagnostic> {
+    let line_of = |i: usize| edited_line(lines, i);
+
+    let mut diagnostics = Vec::new();
+
+    for (line, rule) in rules.iter().enumerate() {
+        match rule {
+            Rule::Renamed { from, to } | Rule::Copied { from, to } => {
+                validate_rename(line, from, to, &mut diagnostics);
+            }
+            Rule::Created { path } => {
+                validate_destination(line, path, &mut diagnostics);
+            }
+            _ => {}
+        }
+    }
+
+    // ---- MOVES INTO OWN DESCENDANT ----
+
+    let into_self: Vec<(usize, &str, &str)> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(line, rule)| match rule {
+            Rule::Renamed { from, to }
+                if from.ends_with('/')
+                    && to != from
+                    && path_starts_with(to, from) =>
+            {
+                Some((line, from.as_str(), to.as_str()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    for &(line, from, to) in &into_self {
+        // children only follow their parent, report the parent once
+        let inherited = into_self.iter().any(|&(_, other, _)| {
+            other != from && path_starts_with(from, other)
+        });
+
+        if !inherited {
+            diagnostics.push(Diagnostic::error(
+                line,
+                DiagnosticKind::IntoOwnDescendant,
+                format!("{from} cannot be moved into itself ({to})"),
+            ));
+        }
+    }
+
+    // ---- DESTINATION CONFLICTS ----
+
+    let mut destinations: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+
+    for (line, rule) in rules.iter().enumerate() {
+        let dest = match rule {
+            Rule::Unchanged { path } => path,
+            Rule::Renamed { to, .. } => to,
+            Rule::Created { path } => path,
+            Rule::Copied { to, .. } => to,
+            Rule::Deleted { .. } => continue,
+        };
+
+        destinations
+            .entry(dest.trim_end_matches('/'))
+            .or_default()
+            .push(line);
+    }
+
+    for (dest, rule_lines) in destinations {
+        if rule_lines.len() < 2 {
+            continue;
+        }
+
+        let untouched = rule_lines
+            .iter()
+            .find(|&&l| matches!(rules[l], Rule::Unchanged { .. }));
+
+        for &line in &rule_lines {
+            if !matches!(
+                rules[line],
+                Rule::Renamed { .. }
+                    | Rule::Created { .. }
+                    | Rule::Copied { .. }
+            ) {
+                continue;
+            }
+
+            let others: Vec<String> = rule_lines
+                .iter()
+                .filter(|&&l| l != line)
+                .map(|&l| (line_of(l) + 1).to_string())
+                .collect();
+
+            diagnostics.push(match untouched {
+                Some(untouched) => Diagnostic::error(
+                    line,
+                    DiagnosticKind::CollidesWithUntouched,
+                    format!(
+                        "{dest} collides with the unchanged entry on line {}",
+                        line_of(*untouched) + 1
+                    ),
+                ),
+                None => Diagnostic::error(
+                    line,
+                    DiagnosticKind::DuplicateDestination,
+                    format!(
+                        "{dest} is also the destination of line {}",
+                        others.join(", ")
+                    ),
+                ),
+            });
+        }
+    }
+
+    for diagnostic in &mut diagnostics {
+        diagnostic.line = line_of(diagnostic.line);
+    }
+
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
+}
+
+// For sessions over several roots, listed as "{root}/" lines with their
+// entries below: roots stay where they are and everything else stays
+// inside of one
+pub fn validate_roots(
+    rules: &[Rule],
+    lines: &[Option<usize>],
+    roots: &[String],
+) -> Vec<Diagnostic> {
+    let line_of = |i: usize| edited_line(lines, i);
+
+    let mut diagnostics = Vec::new();
+
+    for (i, rule) in rules.iter().enumerate() {
+        let (source, dest) = match rule {
+            Rule::Unchanged { .. } => continue,
+            Rule::Renamed { from, to } => (Some(from), Some(to)),
+            Rule::Deleted { path } => (Some(path), None),
+            Rule::Created { path } => (None, Some(path)),
+            Rule::Copied { to, .. } => (None, Some(to)),
+        };
+
+        if let Some(source) = source
+            && roots.contains(source)
+        {
+            diagnostics.push(Diagnostic::error(
+                line_of(i),
+                DiagnosticKind::RootChanged,
+                format!("root {source} cannot be renamed or deleted"),
+            ));
+            continue;
+        }
+
+        if let Some(dest) = dest
+            && !leaves_root(dest)
+            && root_of(dest, roots).is_none_or(|root| root == dest)
+        {
+            diagnostics.push(Diagnostic::error(
+                line_of(i),
+                DiagnosticKind::OutsideRoot,
+                format!("{dest} is not inside any of the roots"),
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+// Destinations taken by entries that are not in the list, which appeared
+// since it was made or are outside of the root. Listed entries are left to
+// `validate_lines`.
+pub fn validate_on_disk(
+    rules: &[Rule],
+    lines: &[Option<usize>],
+    root: &Path,
+    listed: &[String],
+) -> Vec<Diagnostic> {
+    let line_of = |i: usize| edited_line(lines, i);
+    let listed: HashSet<&str> = listed
+        .iter()
+        .map(|entry| entry.trim_end_matches('/'))
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    let mut taken: Vec<&str> = Vec::new();
+
+    for (i, rule) in rules.iter().enumerate() {
+        let dest = match rule {
+            Rule::Renamed { to, .. } | Rule::Copied { to, .. } => to,
+            Rule::Created { path } => path,
+            Rule::Unchanged { .. } | Rule::Deleted { .. } => continue,
+        };
+
+        // entries carried along by a directory are reported with it
+        if listed.contains(dest.trim_end_matches('/'))
+            || taken
+                .iter()
+                .any(|dir| path_starts_with(dest, dir))
+        {
+            continue;
+        }
+
+        if fs::symlink_metadata(root.join(dest.trim_end_matches('/'))).is_ok() {
+            if dest.ends_with('/') {
+                taken.push(dest);
+            }
+            diagnostics.push(Diagnostic::error(
+                line_of(i),
+                DiagnosticKind::ExistsOnDisk,
+                format!("{dest} already exists on disk"),
+            ));
+        }
+    }
+
+    diagnostics
+}
+
+fn validate_rename(
+    line: usize,
+    from: &str,
+    to: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let from_dir = from.ends_with('/');
+    let to_dir = to.ends_with('/');
+
+    if from_dir != to_dir {
+        let message = if to_dir {
+            format!("file {from} cannot be renamed to directory path {to}")
+        } else {
+            format!("directory {from} must keep its trailing '/' in {to}")
+        };
+
+        diagnostics.push(Diagnostic::error(
+            line,
+            DiagnosticKind::KindMismatch,
+            message,
+        ));
+    }
+
+    validate_destination(line, to, diagnostics);
+}
+
+fn validate_destination(
+    line: usize,
+    to: &str,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if to
+        .trim_end_matches('/')
+        .split('/')
+        .any(str::is_empty)
+    {
+        diagnostics.push(Diagnostic::error(
+            line,
+            DiagnosticKind::EmptySegment,
+            format!("{to:?} contains an empty path segment"),
+        ));
+    }
+
+    if let Some(name) = to
+        .trim_end_matches('/')
+        .split('/')
+        .find(|name| name.len() > NAME_MAX)
+    {
+        diagnostics.push(Diagnostic::error(
+            line,
+            DiagnosticKind::NameTooLong,
+            format!(
+                "{}... is longer than {NAME_MAX} bytes",
+                &name[..name.floor_char_boundary(32)]
+            ),
+        ));
+    }
+
+    if leaves_root(to) {
+        diagnostics.push(Diagnostic::error(
+            line,
+            DiagnosticKind::OutsideRoot,
+            format!("{to} is outside of the root"),
+        ));
+    }
+
+    if to.trim() != to {
+        diagnostics.push(Diagnostic {
+            line,
+            severity: Severity::Warning,
+            kind: DiagnosticKind::SurroundingWhitespace,
+            message: format!("{to:?} starts or ends with whitespace"),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_line_rules, build_rules_by_id, normalize_rules};
+
+    use DiagnosticKind::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    // original list, edited list, expected lines and kinds
+    type Case<'a> =
+        (&'a [&'a str], &'a [&'a str], &'a [(usize, DiagnosticKind)]);
+
+    fn found(diagnostics: &[Diagnostic]) -> Vec<(usize, DiagnosticKind)> {
+        diagnostics
+            .iter()
+            .map(|d| (d.line, d.kind))
+            .collect()
+    }
+
+    #[test]
+    fn reports_invalid_edits_on_their_lines() {
+        let cases: [Case; 6] = [
+            (
+                &["a", "b"],
+                &["c", "c"],
+                &[(0, DuplicateDestination), (1, DuplicateDestination)],
+            ),
+            (&["a", "b"], &["b", "b"], &[(0, CollidesWithUntouched)]),
+            (&["a", "x"], &["a", "x/"], &[(1, KindMismatch)]),
+            (
+                &["a/", "a/s/", "a/f"],
+                &["a/b/", "a/s/", "a/f"],
+                &[(0, IntoOwnDescendant)],
+            ),
+            (
+                &["a", "b"],
+                &["a", "b", "+ a"],
+                &[(2, CollidesWithUntouched)],
+            ),
+            (&["a", "b"], &["a", "../b"], &[(1, OutsideRoot)]),
+        ];
+
+        for (original, edited, expected) in cases {
+            let built =
+                build_line_rules(&lines(original), &lines(edited)).unwrap();
+            let rules = normalize_rules(&built.rules);
+
+            assert_eq!(
+                found(&validate_lines(&rules, &built.lines)),
+                expected,
+                "{edited:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn maps_diagnostics_to_reordered_id_lines() {
+        let original = lines(&["r/", "r/a", "r/b", "s/"]);
+        let built = build_rules_by_id(
+            &original,
+            &lines(&["3\tr/x", "1\tr/", "2\tr/x"]),
+        )
+        .unwrap();
+        let rules = normalize_rules(&built.rules);
+
+        let diagnostics = validate_lines(&rules, &built.lines);
+        assert_eq!(
+            found(&diagnostics),
+            [(0, DuplicateDestination), (2, DuplicateDestination)]
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "r/x is also the destination of line 3"
+        );
+
+        // the removed root is reported next to where it was listed
+        let roots = lines(&["r/", "s/"]);
+        assert_eq!(
+            found(&validate_roots(&rules, &built.lines, &roots)),
+            [(0, RootChanged)]
+        );
+    }
+
+    #[test]
+    fn reports_destinations_taken_on_disk() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("dir/sub")).unwrap();
+        fs::write(root.path().join("file"), "").unwrap();
+
+        let original = lines(&["a", "b/", "b/c", "file"]);
+        let built = build_line_rules(
+            &original,
+            &lines(&["file2", "dir/", "dir/c", "x/../file"]),
+        )
+        .unwrap();
+        let rules = normalize_rules(&crate::canonicalize_rules(
+            root.path(),
+            &built.rules,
+        ));
+
+        // listed entries are left to `validate_lines`, children of a taken
+        // directory are reported with it
+        assert_eq!(
+            found(&validate_on_disk(
+                &rules,
+                &built.lines,
+                root.path(),
+                &original
+            )),
+            [(1, ExistsOnDisk)]
+        );
+    }
+}
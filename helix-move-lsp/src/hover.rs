@@ -0,0 +1,133 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use helix_move_lib::{EntryKind, format_timestamp};
+
+// Only the start of a file is read for its preview
+const PREVIEW_BYTES: u64 = 4096;
+const PREVIEW_LINES: usize = 10;
+
+// Markdown describing the listed `entry` as it is on disk
+pub fn describe_entry(root: &Path, entry: &str) -> Option<String> {
+    let path = root.join(entry.trim_end_matches('/'));
+    let metadata = fs::symlink_metadata(&path).ok()?;
+    let kind = EntryKind::of(metadata.file_type());
+
+    let mut text = format!("**{entry}**\n\n- type: {kind}\n");
+
+    match kind {
+        EntryKind::Dir => {
+            let count = fs::read_dir(&path).map_or(0, |dir| dir.count());
+            text.push_str(&format!("- entries: {count}\n"));
+        }
+        _ => {
+            text.push_str(&format!("- size: {}\n", format_size(metadata.len())))
+        }
+    }
+
+    let mode = metadata.permissions().mode();
+    text.push_str(&format!(
+        "- permissions: {} ({:04o})\n",
+        format_mode(mode),
+        mode & 0o7777
+    ));
+
+    if let Ok(modified) = metadata.modified()
+        && let Ok(since_epoch) = modified.duration_since(UNIX_EPOCH)
+    {
+        text.push_str(&format!(
+            "- modified: {}\n",
+            format_timestamp(since_epoch.as_secs())
+        ));
+    }
+
+    if kind == EntryKind::Symlink
+        && let Ok(target) = fs::read_link(&path)
+    {
+        text.push_str(&format!("- target: {}\n", target.display()));
+    }
+
+    // never opened otherwise, reading a fifo would block
+    if kind == EntryKind::File
+        && let Some(preview) = preview(&path)
+    {
+        // longer than any backtick run of the preview itself
+        let longest = preview
+            .split(|c| c != '`')
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let fence = "`".repeat(longest.max(2) + 1);
+        text.push_str(&format!("\n{fence}\n{preview}\n{fence}\n"));
+    }
+
+    Some(text)
+}
+
+// First lines of a text file, None for binary ones
+fn preview(path: &Path) -> Option<String> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(PREVIEW_BYTES)
+        .read_to_end(&mut bytes)
+        .ok()?;
+
+    if bytes.contains(&0) {
+        return None;
+    }
+
+    // the read may end within a character
+    let text = match std::str::from_utf8(&bytes) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&bytes[..e.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    let lines: Vec<&str> = text
+        .lines()
+        .take(PREVIEW_LINES)
+        .collect();
+    Some(lines.join("\n"))
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {} ({bytes} bytes)", UNITS[unit])
+}
+
+// "rwxr-xr-x" style, without the special bits
+fn format_mode(mode: u32) -> String {
+    (0..9)
+        .map(|i| {
+            let set = mode & (0o400 >> i) != 0;
+            match (set, i % 3) {
+                (false, _) => '-',
+                (true, 0) => 'r',
+                (true, 1) => 'w',
+                (true, _) => 'x',
+            }
+        })
+        .collect()
+}
@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
     Arc,
+    atomic::{AtomicBool, Ordering},
 };
 
 use helix_move_lib::*;
@@ -9,19 +10,73 @@ use tokio::io::{stdin, stdout};
 use tokio::sync::RwLock;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
+// the one sent to the client, not the one of helix_move_lib
+use tower_lsp::lsp_types::Diagnostic;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 
+mod hover;
+
 #[derive(serde::Deserialize)]
 struct InitOptions {
     file_list_file: String,
     files: Vec<String>,
+    #[serde(default)]
+    root: Option<PathBuf>,
+    #[serde(default)]
+    roots: Vec<String>,
+    #[serde(default)]
+    allow_outside_root: bool,
+    #[serde(default)]
+    links: BTreeMap<String, String>,
+    #[serde(default)]
+    kinds: BTreeMap<String, String>,
+    #[serde(default)]
+    hard_links: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    ids: bool,
+    /// Theme scope of every kind of line, see `LineClass`
+    #[serde(default)]
+    token_scopes: BTreeMap<String, String>,
+}
+
+// Kinds of lines told apart by semantic tokens, in legend order
+#[derive(Clone, Copy)]
+enum LineClass {
+    Unchanged,
+    /// Renamed within its directory
+    Renamed,
+    /// Moved to another directory
+    Moved,
+    /// Moved along with a renamed directory above it
+    Carried,
+    Deleted,
+    Created,
+    /// Segments of a renamed path that changed
+    Changed,
 }
 
+const LINE_CLASSES: [&str; 7] = [
+    "unchanged",
+    "renamed",
+    "moved",
+    "carried",
+    "deleted",
+    "created",
+    "changed",
+];
+
 struct Backend {
     client: Client,
     file_url: RwLock<Url>,
     original: RwLock<Vec<String>>,
     current: RwLock<Vec<String>>,
+    root: RwLock<Option<PathBuf>>,
+    roots: RwLock<Vec<String>>,
+    allow_outside_root: AtomicBool,
+    links: RwLock<BTreeMap<String, String>>,
+    kinds: RwLock<BTreeMap<String, String>>,
+    hard_links: RwLock<BTreeMap<String, Vec<String>>>,
+    ids: AtomicBool,
     applying_edit: Arc<AtomicBool>,
 }
 
@@ -37,19 +92,45 @@ impl Backend {
             .map(|l| l.to_string())
             .collect();
 
-        // Length must match for positional diff
-        if current.len() != edited.len() {
+        // lines carry their id, there is nothing to line up
+        if self.ids.load(Ordering::SeqCst) {
+            let mut lock = self.current.write().await;
+            *lock = edited;
             return;
         }
 
-        // Phase 1
-        let rules = build_rules(&current, &edited);
+        let files = self.original.read().await.clone();
+
+        // "+ " and "c " lines stay in place, only the others are paired
+        let paired = |lines: &[String]| -> Vec<String> {
+            lines
+                .iter()
+                .zip(paired_lines(&files, lines))
+                .filter(|(_, paired)| *paired)
+                .map(|(line, _)| line.clone())
+                .collect()
+        };
+
+        // Phase 1 (length must match for positional diff)
+        let Ok(rules) = build_rules(&paired(&current), &paired(&edited)) else {
+            return;
+        };
 
         // Phase 2
         let normalized = normalize_rules(&rules);
 
         // Produce normalized list
-        let new_list = apply_rules_to_list(&normalized);
+        let mut normalized_list = apply_rules_to_list(&normalized).into_iter();
+        let new_list: Vec<String> = edited
+            .iter()
+            .zip(paired_lines(&files, &edited))
+            .map(|(line, paired)| match paired {
+                true => normalized_list
+                    .next()
+                    .unwrap_or_default(),
+                false => line.clone(),
+            })
+            .collect();
 
         let new_text = new_list.join("\n");
 
@@ -66,6 +147,98 @@ impl Backend {
         self.update_content().await
     }
 
+    // Rules of the edited list against the original entries, the way the
+    // bin builds them
+    async fn normalized_rules(
+        &self,
+        edited: &[String],
+    ) -> std::result::Result<LineRules, Error> {
+        let files = self.original.read().await;
+
+        let LineRules { rules, lines } = match self.ids.load(Ordering::SeqCst) {
+            true => build_rules_by_id(&files, edited)?,
+            false => build_line_rules(&files, edited)?,
+        };
+
+        let rules = match self.root.read().await.as_deref() {
+            Some(root) => canonicalize_rules(root, &rules),
+            None => rules,
+        };
+
+        Ok(LineRules {
+            rules: normalize_rules(&rules),
+            lines,
+        })
+    }
+
+    // The checks the bin runs before applying, plus destinations taken on
+    // disk
+    async fn publish_diagnostics(&self, content: &str) {
+        let edited: Vec<String> = content
+            .lines()
+            .map(|l| l.to_string())
+            .collect();
+
+        let normalized_rules = self.normalized_rules(&edited).await;
+
+        let files = self.original.read().await;
+        let root = self.root.read().await;
+        let roots = self.roots.read().await;
+
+        let diagnostics = match normalized_rules {
+            Ok(LineRules {
+                rules: normalized,
+                lines,
+            }) => {
+                let mut diagnostics = validate_lines(&normalized, &lines);
+                if !roots.is_empty() {
+                    diagnostics.extend(validate_roots(
+                        &normalized,
+                        &lines,
+                        &roots,
+                    ));
+                }
+                if let Some(root) = root.as_deref() {
+                    diagnostics.extend(validate_on_disk(
+                        &normalized,
+                        &lines,
+                        root,
+                        &files,
+                    ));
+                }
+                if self
+                    .allow_outside_root
+                    .load(Ordering::SeqCst)
+                {
+                    diagnostics
+                        .retain(|d| d.kind != DiagnosticKind::OutsideRoot);
+                }
+
+                diagnostics
+                    .iter()
+                    .map(|d| {
+                        let severity = match d.severity {
+                            Severity::Error => DiagnosticSeverity::ERROR,
+                            Severity::Warning => DiagnosticSeverity::WARNING,
+                        };
+                        line_diagnostic(&edited, d.line, severity, &d.message)
+                    })
+                    .collect()
+            }
+            Err(error) => vec![line_diagnostic(
+                &edited,
+                error_line(&error, &edited),
+                DiagnosticSeverity::ERROR,
+                &error.to_string(),
+            )],
+        };
+
+        let file_url = self.file_url.read().await.clone();
+        self.client
+            .publish_diagnostics(file_url, diagnostics, None)
+            .await;
+    }
+
     async fn update_content(&self) {
         let new_text = {
             let current = self.current.read().await;
@@ -108,24 +281,61 @@ impl LanguageServer for Backend {
             .log_message(MessageType::INFO, "Starting...")
             .await;
 
-        if let Some(value) = params.initialization_options {
-            if let Ok(opts) = serde_json::from_value::<InitOptions>(value) {
-                {
-                    let url = Url::from_file_path(opts.file_list_file).unwrap();
+        let mut token_scopes = BTreeMap::new();
 
-                    let mut lock = self.file_url.write().await;
-                    *lock = url;
-                }
+        if let Some(value) = params.initialization_options
+            && let Ok(opts) = serde_json::from_value::<InitOptions>(value)
+        {
+            token_scopes = opts.token_scopes;
 
-                {
-                    let mut lock = self.original.write().await;
-                    *lock = opts.files.clone();
-                }
+            {
+                let url = Url::from_file_path(opts.file_list_file).unwrap();
 
-                {
-                    let mut lock = self.current.write().await;
-                    *lock = opts.files;
-                }
+                let mut lock = self.file_url.write().await;
+                *lock = url;
+            }
+
+            {
+                let mut lock = self.original.write().await;
+                *lock = opts.files.clone();
+            }
+
+            {
+                let mut lock = self.current.write().await;
+                *lock = match opts.ids {
+                    true => format_with_ids(&opts.files),
+                    false => opts.files,
+                };
+            }
+
+            self.ids
+                .store(opts.ids, Ordering::SeqCst);
+            self.allow_outside_root
+                .store(opts.allow_outside_root, Ordering::SeqCst);
+
+            {
+                let mut lock = self.root.write().await;
+                *lock = opts.root;
+            }
+
+            {
+                let mut lock = self.roots.write().await;
+                *lock = opts.roots;
+            }
+
+            {
+                let mut lock = self.links.write().await;
+                *lock = opts.links;
+            }
+
+            {
+                let mut lock = self.kinds.write().await;
+                *lock = opts.kinds;
+            }
+
+            {
+                let mut lock = self.hard_links.write().await;
+                *lock = opts.hard_links;
             }
         }
 
@@ -135,6 +345,37 @@ impl LanguageServer for Backend {
                     TextDocumentSyncKind::FULL,
                 )),
                 inlay_hint_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                semantic_tokens_provider: Some(
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(
+                        SemanticTokensOptions {
+                            legend: SemanticTokensLegend {
+                                // Helix looks token types up as theme
+                                // scopes, so they are named after them
+                                token_types: LINE_CLASSES
+                                    .iter()
+                                    .map(|class| {
+                                        token_scopes
+                                            .get(*class)
+                                            .cloned()
+                                            .unwrap_or(class.to_string())
+                                            .into()
+                                    })
+                                    .collect(),
+                                token_modifiers: Vec::new(),
+                            },
+                            full: Some(SemanticTokensFullOptions::Bool(true)),
+                            ..Default::default()
+                        },
+                    ),
+                ),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(vec!["/".to_string()]),
+                    ..Default::default()
+                }),
+                code_action_provider: Some(
+                    CodeActionProviderCapability::Simple(true),
+                ),
                 ..Default::default()
             },
             ..Default::default()
@@ -146,15 +387,22 @@ impl LanguageServer for Backend {
         _params: InlayHintParams,
     ) -> Result<Option<Vec<InlayHint>>> {
         let files = self.original.read().await;
+        let links = self.links.read().await;
+        let kinds = self.kinds.read().await;
+        let hard_links = self.hard_links.read().await;
+        let current = self.current.read().await;
+
+        let rows =
+            original_rows(&files, &current, self.ids.load(Ordering::SeqCst));
+
         let max_width = files
             .iter()
             .map(|f| f.len())
             .max()
             .unwrap_or(0);
-        let hints = files
+        let mut hints: Vec<InlayHint> = rows
             .iter()
-            .enumerate()
-            .map(|(i, f)| InlayHint {
+            .map(|&(i, f)| InlayHint {
                 position: Position {
                     line: i as u32,
                     character: 0,
@@ -172,9 +420,374 @@ impl LanguageServer for Backend {
                 data: None,
             })
             .collect();
+
+        // symlinks show their target behind the line, special files their
+        // kind and hard links the other listed links to their inode
+        for &(i, f) in &rows {
+            let mut labels = Vec::new();
+
+            if let Some(target) = links.get(f) {
+                labels.push(format!("@ -> {target}"));
+            }
+            if let Some(kind) = kinds.get(f) {
+                labels.push(format!("({kind})"));
+            }
+            match hard_links.get(f) {
+                Some(others) if others.is_empty() => {
+                    labels.push("(linked outside the list)".to_string());
+                }
+                Some(others) => {
+                    labels.push(format!("(linked to {})", others.join(", ")));
+                }
+                None => {}
+            }
+
+            if labels.is_empty() {
+                continue;
+            }
+
+            let end = current
+                .get(i)
+                .map(|line| line.encode_utf16().count())
+                .unwrap_or(0);
+
+            hints.push(InlayHint {
+                position: Position {
+                    line: i as u32,
+                    character: end as u32,
+                },
+                label: InlayHintLabel::String(labels.join(" ")),
+                kind: Some(InlayHintKind::TYPE),
+                text_edits: None,
+                tooltip: None,
+                padding_left: Some(true),
+                padding_right: None,
+                data: None,
+            });
+        }
+
         Ok(Some(hints))
     }
 
+    async fn semantic_tokens_full(
+        &self,
+        _params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let current = self.current.read().await.clone();
+        let ids = self.ids.load(Ordering::SeqCst);
+
+        let Ok(LineRules { rules, lines }) =
+            self.normalized_rules(&current).await
+        else {
+            return Ok(None);
+        };
+
+        // entries that only moved because a directory above them did
+        let carried: HashSet<&str> = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::Renamed { from, .. } => Some(from),
+                _ => None,
+            })
+            .flat_map(|dir| affected_entries(&rules, dir))
+            .filter_map(|rule| match rule {
+                Rule::Renamed { from, .. } => Some(from.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        // (line, start, length, class), in UTF-16 units
+        let mut spans: Vec<(u32, u32, u32, LineClass)> = Vec::new();
+
+        for (rule, line) in rules.iter().zip(&lines) {
+            let Some(text) = line.and_then(|line| current.get(line)) else {
+                continue;
+            };
+            let line = line.unwrap_or_default() as u32;
+            let (id, path) = split_id(text, ids);
+
+            let (class, original) = match rule {
+                Rule::Unchanged { .. } => (LineClass::Unchanged, None),
+                Rule::Deleted { .. } => (LineClass::Deleted, None),
+                Rule::Created { .. } | Rule::Copied { .. } => {
+                    (LineClass::Created, None)
+                }
+                Rule::Renamed { from, .. }
+                    if carried.contains(from.as_str()) =>
+                {
+                    (LineClass::Carried, Some(from.clone()))
+                }
+                Rule::Renamed { from, to } => {
+                    // where the entry would be had only its name changed,
+                    // its directory may have been renamed as well
+                    let dir = rules
+                        .iter()
+                        .find_map(|rule| match rule {
+                            Rule::Renamed { from: f, to: t }
+                                if f.trim_end_matches('/') == parent(from) =>
+                            {
+                                Some(t.trim_end_matches('/'))
+                            }
+                            _ => None,
+                        })
+                        .unwrap_or(parent(from));
+
+                    match dir == parent(to) {
+                        true => (
+                            LineClass::Renamed,
+                            Some(match dir {
+                                "" => name(from).to_string(),
+                                dir => format!("{dir}/{}", name(from)),
+                            }),
+                        ),
+                        false => (LineClass::Moved, Some(from.clone())),
+                    }
+                }
+            };
+
+            let utf16 = |s: &str| s.encode_utf16().count() as u32;
+
+            match original.and_then(|original| changed_span(&original, path)) {
+                Some((start, end)) => {
+                    let start = id.len() + start;
+                    let end = id.len() + end;
+                    let before = utf16(&text[..start]);
+                    let changed = utf16(&text[start..end]);
+
+                    spans.push((line, 0, before, class));
+                    spans.push((line, before, changed, LineClass::Changed));
+                    spans.push((
+                        line,
+                        before + changed,
+                        utf16(&text[end..]),
+                        class,
+                    ));
+                }
+                None => spans.push((line, 0, utf16(text), class)),
+            }
+        }
+
+        spans.retain(|&(_, _, length, _)| length > 0);
+        spans.sort_by_key(|&(line, start, _, _)| (line, start));
+
+        // positions are relative to the previous token
+        let mut data = Vec::new();
+        let (mut last_line, mut last_start) = (0, 0);
+
+        for (line, start, length, class) in spans {
+            data.push(SemanticToken {
+                delta_line: line - last_line,
+                delta_start: match line == last_line {
+                    true => start - last_start,
+                    false => start,
+                },
+                length,
+                token_type: class as u32,
+                token_modifiers_bitset: 0,
+            });
+            (last_line, last_start) = (line, start);
+        }
+
+        Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data,
+        })))
+    }
+
+    async fn completion(
+        &self,
+        params: CompletionParams,
+    ) -> Result<Option<CompletionResponse>> {
+        let files = self.original.read().await;
+        let current = self.current.read().await;
+        let ids = self.ids.load(Ordering::SeqCst);
+
+        let position = params.text_document_position.position;
+        let Some(line) = current.get(position.line as usize) else {
+            return Ok(None);
+        };
+
+        let typed =
+            line_path(&line[..byte_offset(line, position.character)], ids);
+
+        // completes the segment after the last '/'
+        let (parent, segment) = match typed.rfind('/') {
+            Some(slash) => typed.split_at(slash + 1),
+            None => ("", typed),
+        };
+
+        // directories of the original tree and the ones the edited lines
+        // introduce
+        let edited = current
+            .iter()
+            .map(|line| line_path(line, ids))
+            .filter(|path| !path.starts_with("- "));
+
+        let mut dirs = BTreeSet::new();
+        for path in files
+            .iter()
+            .map(String::as_str)
+            .chain(edited)
+        {
+            for (i, _) in path.match_indices('/') {
+                dirs.insert(&path[..=i]);
+            }
+        }
+
+        let start = Position {
+            line: position.line,
+            character: position.character
+                - segment.encode_utf16().count() as u32,
+        };
+
+        let items: Vec<CompletionItem> = dirs
+            .into_iter()
+            .filter_map(|dir| dir.strip_prefix(parent))
+            .filter(|name| {
+                !name.is_empty()
+                    && name
+                        .trim_end_matches('/')
+                        .find('/')
+                        .is_none()
+                    && name.starts_with(segment)
+            })
+            .map(|name| CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::FOLDER),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                    range: Range {
+                        start,
+                        end: position,
+                    },
+                    new_text: name.to_string(),
+                })),
+                ..Default::default()
+            })
+            .collect();
+
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let Some(root) = self.root.read().await.clone() else {
+            return Ok(None);
+        };
+
+        let files = self.original.read().await;
+        let current = self.current.read().await;
+        let line = params
+            .text_document_position_params
+            .position
+            .line as usize;
+
+        let Some(&(_, entry)) =
+            original_rows(&files, &current, self.ids.load(Ordering::SeqCst))
+                .iter()
+                .find(|(i, _)| *i == line)
+        else {
+            return Ok(None);
+        };
+
+        Ok(hover::describe_entry(&root, entry).map(|text| Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        }))
+    }
+
+    async fn code_action(
+        &self,
+        params: CodeActionParams,
+    ) -> Result<Option<CodeActionResponse>> {
+        let files = self.original.read().await;
+        let current = self.current.read().await;
+        let ids = self.ids.load(Ordering::SeqCst);
+
+        let rows = original_rows(&files, &current, ids);
+        let selected =
+            params.range.start.line as usize..=params.range.end.line as usize;
+
+        let action = |title: String, edits: Vec<TextEdit>| {
+            CodeActionOrCommand::CodeAction(CodeAction {
+                title,
+                kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                edit: Some(WorkspaceEdit {
+                    changes: Some(HashMap::from([(
+                        params.text_document.uri.clone(),
+                        edits,
+                    )])),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        };
+
+        let mut actions = Vec::new();
+
+        for &(i, original) in rows
+            .iter()
+            .filter(|(i, _)| selected.contains(i))
+        {
+            let line = &current[i];
+            let (id, path) = split_id(line, ids);
+
+            if let Some(kept) = path.strip_prefix("- ") {
+                // deleted lines lose their path once normalized
+                let restored = match kept.trim() {
+                    "" => original.as_str(),
+                    _ => kept,
+                };
+
+                actions.push(action(
+                    format!("Undo deletion of {original}"),
+                    vec![line_edit(i, line, format!("{id}{restored}"))],
+                ));
+            } else {
+                if path != original {
+                    actions.push(action(
+                        format!("Revert to {original}"),
+                        vec![line_edit(i, line, format!("{id}{original}"))],
+                    ));
+                }
+
+                actions.push(action(
+                    format!("Mark {original} as deleted"),
+                    vec![line_edit(i, line, format!("{id}- {path}"))],
+                ));
+            }
+
+            if !original.ends_with('/') {
+                continue;
+            }
+
+            let edits: Vec<TextEdit> = rows
+                .iter()
+                .filter(|(_, entry)| entry.starts_with(original.as_str()))
+                .filter_map(|&(j, entry)| {
+                    let (id, path) = split_id(&current[j], ids);
+                    (path != entry).then(|| {
+                        line_edit(j, &current[j], format!("{id}{entry}"))
+                    })
+                })
+                .collect();
+
+            // otherwise it is just the revert above
+            if edits
+                .iter()
+                .any(|edit| edit.range.start.line as usize != i)
+            {
+                actions.push(action(
+                    format!("Revert {original} and everything in it"),
+                    edits,
+                ));
+            }
+        }
+
+        Ok(Some(actions))
+    }
+
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         if self
             .applying_edit
@@ -192,6 +805,7 @@ impl LanguageServer for Backend {
         };
 
         self.apply_change(&content).await;
+        self.publish_diagnostics(&content).await;
 
         self.applying_edit
             .store(false, Ordering::SeqCst);
@@ -202,6 +816,173 @@ impl LanguageServer for Backend {
     }
 }
 
+// Original entry of every line that has one
+fn original_rows<'a>(
+    files: &'a [String],
+    current: &[String],
+    ids: bool,
+) -> Vec<(usize, &'a String)> {
+    match ids {
+        true => current
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let id = parse_id_line(line).0?;
+                Some((i, files.get(id.checked_sub(1)?)?))
+            })
+            .collect(),
+        false => paired_lines(files, current)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, paired)| *paired)
+            .map(|(i, _)| i)
+            .zip(files.iter())
+            .collect(),
+    }
+}
+
+// Splits the "{id}\t" in front of the path off a line
+fn split_id(line: &str, ids: bool) -> (&str, &str) {
+    match line.find('\t') {
+        Some(tab) if ids => line.split_at(tab + 1),
+        _ => ("", line),
+    }
+}
+
+fn name(path: &str) -> &str {
+    let path = path.trim_end_matches('/');
+    path.rsplit_once('/')
+        .map_or(path, |(_, name)| name)
+}
+
+fn parent(path: &str) -> &str {
+    path.trim_end_matches('/')
+        .rsplit_once('/')
+        .map_or("", |(parent, _)| parent)
+}
+
+// Byte range of the segments of `path` that differ from `original`, None
+// when segments were only removed
+fn changed_span(original: &str, path: &str) -> Option<(usize, usize)> {
+    let old: Vec<&str> = original
+        .trim_end_matches('/')
+        .split('/')
+        .collect();
+    let new: Vec<&str> = path
+        .trim_end_matches('/')
+        .split('/')
+        .collect();
+
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let changed = &new[prefix..new.len() - suffix];
+    if changed.is_empty() {
+        return None;
+    }
+
+    let start: usize = new[..prefix]
+        .iter()
+        .map(|s| s.len() + 1)
+        .sum();
+    let length = changed
+        .iter()
+        .map(|s| s.len() + 1)
+        .sum::<usize>()
+        - 1;
+    Some((start, start + length))
+}
+
+// Path of a line, without its id and "+ " or "c "
+fn line_path(line: &str, ids: bool) -> &str {
+    let (_, path) = split_id(line, ids);
+    created_path(path)
+        .or_else(|| copied_path(path))
+        .unwrap_or(path)
+}
+
+// Byte offset of a UTF-16 column, clamped to the end of the line
+fn byte_offset(line: &str, character: u32) -> usize {
+    let mut units = 0;
+
+    for (offset, c) in line.char_indices() {
+        if units >= character as usize {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+
+    line.len()
+}
+
+// Replaces a whole line
+fn line_edit(line: usize, text: &str, new_text: String) -> TextEdit {
+    TextEdit {
+        range: Range {
+            start: Position {
+                line: line as u32,
+                character: 0,
+            },
+            end: Position {
+                line: line as u32,
+                character: text.encode_utf16().count() as u32,
+            },
+        },
+        new_text,
+    }
+}
+
+// Line a failed build is reported on
+fn error_line(error: &Error, edited: &[String]) -> usize {
+    match error {
+        Error::NothingToCopy { line } => line.saturating_sub(1),
+        Error::UnknownId { id } => edited
+            .iter()
+            .position(|line| parse_id_line(line).0 == Some(*id))
+            .unwrap_or(0),
+        // lines were added or removed somewhere, the end is as good as any
+        _ => edited.len().saturating_sub(1),
+    }
+}
+
+fn line_diagnostic(
+    edited: &[String],
+    line: usize,
+    severity: DiagnosticSeverity,
+    message: &str,
+) -> Diagnostic {
+    let end = edited
+        .get(line)
+        .map(|line| line.encode_utf16().count())
+        .unwrap_or(0);
+
+    Diagnostic {
+        range: Range {
+            start: Position {
+                line: line as u32,
+                character: 0,
+            },
+            end: Position {
+                line: line as u32,
+                character: end as u32,
+            },
+        },
+        severity: Some(severity),
+        source: Some("helix-move".to_string()),
+        message: message.to_string(),
+        ..Default::default()
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let (service, socket) = LspService::new(|client| Backend {
@@ -209,6 +990,13 @@ async fn main() {
         file_url: RwLock::new(Url::parse("file:///placeholder").unwrap()),
         original: RwLock::new(Vec::new()),
         current: RwLock::new(Vec::new()),
+        root: RwLock::new(None),
+        roots: RwLock::new(Vec::new()),
+        allow_outside_root: AtomicBool::new(false),
+        links: RwLock::new(BTreeMap::new()),
+        kinds: RwLock::new(BTreeMap::new()),
+        hard_links: RwLock::new(BTreeMap::new()),
+        ids: AtomicBool::new(false),
         applying_edit: Arc::new(AtomicBool::new(false)),
     });
 